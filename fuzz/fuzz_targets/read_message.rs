@@ -0,0 +1,44 @@
+//! Feeds arbitrary bytes to `HandshakeState::read_message` at every stage of every handshake pattern,
+//! malformed messages must be rejected with an error rather than a panic.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use noise_handshake::auth::noise::{
+    handshake_state::{HandshakeState, StaticKeypair},
+    pattern::HandshakePattern,
+    MessagePattern,
+};
+
+const PATTERNS: [&str; 18] = [
+    "N", "K", "X", "NN", "NK", "NX", "XN", "XK", "XX", "KN", "KK", "KX", "IN", "IK", "IX", "NNpsk0", "XXpsk3",
+    "IKpsk2",
+];
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&selector, message)) = data.split_first() else {
+        return;
+    };
+    let pattern = HandshakePattern::new(PATTERNS[selector as usize % PATTERNS.len()]).unwrap();
+    let static_local = StaticKeypair::new();
+    let static_remote = StaticKeypair::new();
+    let local_rs = pattern.responder_pre().contains(&MessagePattern::S).then_some(static_remote.0);
+    let remote_rs = pattern.initiator_pre().contains(&MessagePattern::S).then_some(static_local.0);
+    let mut hss_local: HandshakeState =
+        HandshakeState::new(pattern.clone(), true, &[], static_local, None, local_rs, None).unwrap();
+    let mut hss_remote: HandshakeState =
+        HandshakeState::new(pattern.clone(), false, &[], static_remote, None, remote_rs, None).unwrap();
+    if pattern.is_psk() {
+        hss_local = hss_local.with_psks(vec![[0; 32]]);
+        hss_remote = hss_remote.with_psks(vec![[0; 32]]);
+    }
+
+    // The upper bits of the selector pick how many honest messages are exchanged first
+    let stage = (selector as usize / PATTERNS.len()) % pattern.messages().len();
+    let (mut writer, mut reader) = (&mut hss_local, &mut hss_remote);
+    for _ in 0..stage {
+        let honest = writer.write_message(&[]).unwrap();
+        reader.read_message(&honest).unwrap();
+        std::mem::swap(&mut writer, &mut reader);
+    }
+    let _ = reader.read_message(message);
+});
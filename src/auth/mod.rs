@@ -1,9 +1,16 @@
-use ed25519_dalek::Keypair;
-
-use crate::connection::Connection;
-use std::error::Error;
+use crate::{
+    connection::{AsyncConnection, Connection},
+    handshake,
+    identity::Keypair,
+    muxer::MuxerProtocol,
+    peer_id::PeerId,
+};
+use std::{error::Error, future::Future};
 pub mod noise;
 
+use noise::protocol::{NoiseError, RekeyPolicy};
+
+#[derive(Clone, Copy)]
 pub enum AuthProtocol {
     Noise,
 }
@@ -13,6 +20,13 @@ impl AuthProtocol {
             AuthProtocol::Noise => b"/noise\n"
         }
     }
+
+    /// The protocol id negotiated with multistream-select, [AuthProtocol::name] without the trailing newline.
+    pub fn protocol(&self) -> &str {
+        match *self {
+            AuthProtocol::Noise => "/noise",
+        }
+    }
 }
 
 pub trait HandShake<'a, C: Connection> {
@@ -23,9 +37,17 @@ pub trait HandShake<'a, C: Connection> {
     /// so the raw connection can never be used to communicate.
     /// [`Reader`] is a function that takes a connection and reads content from the underlying stream.
     /// [`Writer`] is a function that takes a connection and and some encrypted content and write it to the underlying stream.
+    /// `remote_peer` is the [PeerId] the remote peer must prove it holds the key of, the upgrade fails when it
+    /// authenticates as any other peer. [None] accepts any peer that completes the handshake.
+    /// `initiator` selects whether the local peer dialed the connection or accepted it.
+    /// `muxers` are advertised during the handshake so a stream muxer can be agreed without a further
+    /// negotiation round trip, see [SecureChannel::muxer]. An empty slice skips early muxer negotiation.
     fn upgrade<Reader, Writer>(
         connection: C,
         peer_id: Keypair,
+        remote_peer: Option<PeerId>,
+        initiator: bool,
+        muxers: &[MuxerProtocol],
         reader: Reader,
         writer: Writer,
     ) -> Result<Self::Channel<'a>, Box<dyn Error>>
@@ -41,4 +63,82 @@ pub trait SecureChannel {
 
     /// A function that allows a secure channel to read securely from the underlying stream.
     fn read(&mut self) -> Result<Vec<u8>, Box<dyn Error>>;
+
+    /// The stream muxer both peers agreed on during the handshake, if any.
+    fn muxer(&self) -> Option<MuxerProtocol> {
+        None
+    }
+
+    /// The [PeerId] the remote peer authenticated as, [None] for channels that do not authenticate their peer.
+    fn remote_peer(&self) -> Option<&PeerId> {
+        None
+    }
+
+    /// The identity key the remote peer proved it holds during the handshake.
+    fn remote_public_key(&self) -> Option<&handshake::PublicKey> {
+        None
+    }
+
+    /// The remote peer's static key from the handshake, such as its noise static key.
+    fn remote_static(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// The final handshake hash, it is the same for both peers and unique to the session so it can be used for channel binding.
+    fn handshake_hash(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// Agree on a [RekeyPolicy] with the remote peer before any other transport message and apply it.
+    /// Channels without transport ciphers of their own fail with [NoiseError::RekeyUnsupported].
+    fn set_rekey_policy(&mut self, _policy: RekeyPolicy) -> Result<(), Box<dyn Error>> {
+        Err(NoiseError::RekeyUnsupported().into())
+    }
+}
+
+pub trait AsyncHandShake<C: AsyncConnection> {
+    type Channel: AsyncSecureChannel;
+
+    /// Async counterpart of [HandShake::upgrade], handshake messages are read from and written to
+    /// the connection's byte stream directly so no reader or writer functions are required.
+    fn upgrade(
+        connection: C,
+        peer_id: Keypair,
+        remote_peer: Option<PeerId>,
+        initiator: bool,
+        muxers: &[MuxerProtocol],
+    ) -> impl Future<Output = Result<Self::Channel, Box<dyn Error>>> + Send;
+}
+
+pub trait AsyncSecureChannel {
+    /// A function that allows a secure channel to securely write to the underlying stream.
+    fn write(&mut self, data: &[u8]) -> impl Future<Output = Result<(), Box<dyn Error>>> + Send;
+
+    /// A function that allows a secure channel to read securely from the underlying stream.
+    fn read(&mut self) -> impl Future<Output = Result<Vec<u8>, Box<dyn Error>>> + Send;
+
+    /// The stream muxer both peers agreed on during the handshake, if any.
+    fn muxer(&self) -> Option<MuxerProtocol> {
+        None
+    }
+
+    /// The [PeerId] the remote peer authenticated as, [None] for channels that do not authenticate their peer.
+    fn remote_peer(&self) -> Option<&PeerId> {
+        None
+    }
+
+    /// The identity key the remote peer proved it holds during the handshake.
+    fn remote_public_key(&self) -> Option<&handshake::PublicKey> {
+        None
+    }
+
+    /// The remote peer's static key from the handshake, such as its noise static key.
+    fn remote_static(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// The final handshake hash, it is the same for both peers and unique to the session so it can be used for channel binding.
+    fn handshake_hash(&self) -> Option<&[u8]> {
+        None
+    }
 }
@@ -1,14 +1,7 @@
-use chacha20poly1305::{
-    aead::{Aead, KeyInit, Payload},
-    ChaCha20Poly1305, Key, Nonce,
-};
-use sha2::Sha256;
-use x25519_dalek::{PublicKey, StaticSecret};
-
-use hmac::{Hmac, Mac};
 use std::error::Error;
+use std::marker::PhantomData;
 
-type HmacSha256 = Hmac<Sha256>;
+use super::crypto::{ChaChaPoly, Cipher, Hash};
 
 pub type CipherKey = Vec<u8>;
 
@@ -19,24 +12,31 @@ pub enum CipherError {
     #[error("could not decrypt payload")]
     DecryptionFail(),
     #[error("failed to encrypt data")]
-    EncryptionFail()
+    EncryptionFail(),
+    #[error("the nonce has reached 2^64-1, the cipher must not be used again")]
+    NonceExhausted(),
 }
 
 
 /// An implementation of the [`CipherState`] object from the noise protocol.
 ///
 /// See [CipherState](https://noiseprotocol.org/noise.html#the-cipherstate-object)
-pub struct CipherState {
+pub struct CipherState<C: Cipher = ChaChaPoly> {
     pub k: Option<CipherKey>, //32 bytes
     n: u64,                   //unsigned int nonce
+    cipher: PhantomData<C>,
 }
 
-impl CipherState {
+impl<C: Cipher> CipherState<C> {
     /// A helper function that fufills the role of `InitializeKey(empty)` from the noise spec.
     ///
     /// See [InitializeKey](https://noiseprotocol.org/noise.html#the-cipherstate-object)
     pub fn new() -> Self {
-        CipherState { k: None, n: 0 }
+        CipherState {
+            k: None,
+            n: 0,
+            cipher: PhantomData,
+        }
     }
 
     /// A function that calls `InitializeKey(key)` on the `CipherState` object defined in the protocol.
@@ -55,33 +55,30 @@ impl CipherState {
         self.k.is_some()
     }
 
-    /// A helper function that constructs the 96 bit nonce used in the [chacha20poly1305::ChaCha20Poly1305] cipher.
-    fn get_current_nonce(&self) -> Nonce {
-        let nb: [u8; 8] = self.n.to_le_bytes();
-        let mut nonce: [u8; 12] = [0; 12];
-        nonce[4..12].copy_from_slice(&nb[..(12 - 4)]);
-        Nonce::from(nonce)
+    /// Nonce 2^64-1 is reserved for `Rekey()`, so a cipher that reaches it can no longer be used
+    /// without reusing a nonce.
+    fn check_nonce(&self) -> Result<(), CipherError> {
+        if self.n == u64::MAX {
+            return Err(CipherError::NonceExhausted());
+        }
+        Ok(())
+    }
+
+    /// Starts the nonce counter at `n` so tests can exercise nonce exhaustion.
+    #[cfg(test)]
+    pub(crate) fn set_nonce(&mut self, n: u64) {
+        self.n = n;
     }
 
     /// A function that calls `EncryptWithAd` on the `CipherState` object defined in the protocol.
-    /// Auto-increments the nonce value.
+    /// Auto-increments the nonce value, and fails with [CipherError::NonceExhausted] rather than reuse a nonce.
     ///  
     /// See [EncryptWithAd](https://noiseprotocol.org/noise.html#the-cipherstate-object)
     pub fn encrypt_with_ad(&mut self, ad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
         let key = self.k.as_ref();
         if let Some(k) = key {
-            let cipher = ChaCha20Poly1305::new(Key::from_slice(k));
-            let result = match cipher
-                .encrypt(
-                    &self.get_current_nonce(),
-                    Payload {
-                        msg: plaintext,
-                        aad: ad,
-                    },
-                ) {
-                    Ok(payload) => payload,
-                    Err(_) => return Err(CipherError::EncryptionFail().into())
-                };
+            self.check_nonce()?;
+            let result = C::encrypt(k, self.n, ad, plaintext)?;
             self.n += 1;
             Ok(result)
         } else {
@@ -89,25 +86,15 @@ impl CipherState {
         }
     }
 
-    /// A function that calls `EncryptWithAd` on the `CipherState` object defined in the protocol.
-    /// Auto increments the nonce value.
+    /// A function that calls `DecryptWithAd` on the `CipherState` object defined in the protocol.
+    /// Auto increments the nonce value, and fails with [CipherError::NonceExhausted] once the nonce reaches 2^64-1.
     ///
     /// See [CipherState](https://noiseprotocol.org/noise.html#the-cipherstate-object)
     pub fn decrypt_with_ad(&mut self, ad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
         let key = self.k.as_ref();
         if let Some(k) = key {
-            let cipher = ChaCha20Poly1305::new(Key::from_slice(k));
-            let result = match cipher
-                .decrypt(
-                    &self.get_current_nonce(),
-                    Payload {
-                        msg: ciphertext,
-                        aad: ad,
-                    },
-                ) {
-                    Ok(content) => content,
-                    Err(_) => return Err(CipherError::DecryptionFail().into())
-                };
+            self.check_nonce()?;
+            let result = C::decrypt(k, self.n, ad, ciphertext)?;
             self.n += 1;
             Ok(result)
         } else {
@@ -115,60 +102,88 @@ impl CipherState {
         }
     }
 
-    /// Skeleta implementation of `Rekey()` from the spec.
-    /// Libp2p does not support `Rekey` as part of their connection specification.
+    /// A function that calls `Rekey` on the `CipherState` object defined in the protocol.
+    /// The new key is the first 32 bytes of 32 zeros encrypted under the maximum nonce, the nonce counter is unchanged.
+    ///
+    /// Libp2p does not support `Rekey` as part of their connection specification, so it should only be used
+    /// between peers that have agreed to it, see [crate::auth::noise::protocol::RekeyPolicy].
     ///
-    /// See the libp2p connection spec [here](https://github.com/libp2p/specs/tree/master/connections).
-    /// See the libp2p noise spec [here](https://github.com/libp2p/specs/blob/master/noise/README.md).
-    pub fn rekey(&mut self) {
-        todo!()
+    /// See [Rekey](https://noiseprotocol.org/noise.html#rekey)
+    pub fn rekey(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(k) = self.k.as_ref() {
+            self.k = Some(C::rekey(k)?);
+        }
+        Ok(())
     }
 }
 
 
-impl Default for CipherState {
+impl<C: Cipher> Default for CipherState<C> {
     fn default() -> Self {
         Self::new()
     }
 }
 
 
-/// Implementation of the HKDF function specified in the noise protocol.
+/// Implementation of the HKDF function specified in the noise protocol, generic over the suite's [Hash].
 ///
 /// See [HKDF()](https://noiseprotocol.org/noise.html#hash-functions)
-pub fn hkdf(
+pub fn hkdf<H: Hash>(
     chaining_key: &[u8],
     input_key_material: &[u8],
     num_outputs: usize,
 ) -> (Vec<u8>, Vec<u8>, Option<Vec<u8>>) {
     // Derive temp key
-    let mut mac = <HmacSha256 as Mac>::new_from_slice(chaining_key).unwrap();
-    mac.update(input_key_material);
-    let temp_key = mac.finalize_reset().into_bytes();
+    let temp_key = H::hmac(chaining_key, &[input_key_material]);
 
     // Compute hkdf from temp key
-    let mut mac = <HmacSha256 as Mac>::new_from_slice(&temp_key).unwrap();
-    mac.update(&[1u8]);
-    let output1 = mac.finalize_reset().into_bytes();
-    let in2 = [&output1[..], &[2u8][..]].concat();
-    mac.update(&in2);
-    let output2 = mac.finalize_reset().into_bytes();
+    let output1 = H::hmac(&temp_key, &[&[1u8]]);
+    let output2 = H::hmac(&temp_key, &[&output1, &[2u8]]);
     if num_outputs == 2 {
-        (output1.to_vec(), output2.to_vec(), None)
+        (output1, output2, None)
     } else {
-        let in3 = [&output2[..], &[3u8][..]].concat();
-        mac.update(&in3);
-        let output3 = mac.finalize_reset().into_bytes();
-        (output1.to_vec(), output2.to_vec(), Some(output3.to_vec()))
+        let output3 = H::hmac(&temp_key, &[&output2, &[3u8]]);
+        (output1, output2, Some(output3))
     }
 }
 
-/// Implements the [x25519_dalek] `dh` function specified as part of the noise protocol.
-///
-/// See [DH()](https://noiseprotocol.org/noise.html#dh-functions)
-pub fn dh_static(local_private: &StaticSecret, remote_public: &PublicKey) -> Vec<u8> {
-    local_private
-        .diffie_hellman(remote_public)
-        .as_bytes()
-        .to_vec()
+#[cfg(test)]
+mod tests {
+    use super::{CipherError, CipherState};
+    use crate::auth::noise::crypto::ChaChaPoly;
+
+    #[test]
+    fn test_rekey() {
+        let (mut encrypter, mut decrypter, mut stale) = (
+            CipherState::<ChaChaPoly>::new(),
+            CipherState::<ChaChaPoly>::new(),
+            CipherState::<ChaChaPoly>::new(),
+        );
+        for cipher in [&mut encrypter, &mut decrypter, &mut stale] {
+            cipher.initialise_key(&[3u8; 32]);
+        }
+
+        encrypter.rekey().unwrap();
+        decrypter.rekey().unwrap();
+        let ciphertext = encrypter.encrypt_with_ad(&[], b"rekeyed").unwrap();
+        assert!(stale.decrypt_with_ad(&[], &ciphertext).is_err());
+        assert_eq!(decrypter.decrypt_with_ad(&[], &ciphertext).unwrap(), b"rekeyed");
+    }
+
+    #[test]
+    fn test_nonce_exhaustion() {
+        let (mut encrypter, mut decrypter) = (CipherState::<ChaChaPoly>::new(), CipherState::<ChaChaPoly>::new());
+        for cipher in [&mut encrypter, &mut decrypter] {
+            cipher.initialise_key(&[5u8; 32]);
+            cipher.set_nonce(u64::MAX - 1);
+        }
+
+        // The last usable nonce is 2^64-2, after which both directions refuse to continue
+        let ciphertext = encrypter.encrypt_with_ad(&[], b"last").unwrap();
+        assert_eq!(decrypter.decrypt_with_ad(&[], &ciphertext).unwrap(), b"last");
+        for result in [encrypter.encrypt_with_ad(&[], b"again"), decrypter.decrypt_with_ad(&[], &ciphertext)] {
+            let error = result.unwrap_err();
+            assert!(matches!(error.downcast_ref::<CipherError>(), Some(CipherError::NonceExhausted())));
+        }
+    }
 }
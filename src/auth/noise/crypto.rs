@@ -0,0 +1,324 @@
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305,
+};
+use hmac::{digest::core_api::BlockSizeUser, Mac, SimpleHmac};
+use rand::{rngs::OsRng, RngCore};
+use sha2::Digest;
+use thiserror::Error;
+
+use super::cipher::CipherError;
+use super::x448;
+
+#[derive(Error, Debug)]
+pub enum DhError {
+    #[error("public key must be {0} bytes")]
+    InvalidPublicKey(usize),
+    #[error("private key must be {0} bytes")]
+    InvalidPrivateKey(usize),
+}
+
+/// The cipher functions of a noise suite.
+///
+/// See [Cipher functions](https://noiseprotocol.org/noise.html#cipher-functions)
+pub trait Cipher {
+    /// The name of the cipher as it appears in the protocol name, e.g. `ChaChaPoly`.
+    fn name() -> &'static str;
+
+    /// `ENCRYPT(k, n, ad, plaintext)`
+    fn encrypt(k: &[u8], n: u64, ad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, CipherError>;
+
+    /// `DECRYPT(k, n, ad, ciphertext)`
+    fn decrypt(k: &[u8], n: u64, ad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, CipherError>;
+
+    /// `REKEY(k)`, the first 32 bytes of 32 zeros encrypted under the maximum nonce.
+    fn rekey(k: &[u8]) -> Result<Vec<u8>, CipherError> {
+        let key = Self::encrypt(k, u64::MAX, &[], &[0u8; 32])?;
+        Ok(key[..32].to_vec())
+    }
+}
+
+/// The hash functions of a noise suite.
+///
+/// See [Hash functions](https://noiseprotocol.org/noise.html#hash-functions)
+pub trait Hash {
+    /// The number of bytes output by the hash function.
+    const HASHLEN: usize;
+
+    /// The name of the hash as it appears in the protocol name, e.g. `SHA256`.
+    fn name() -> &'static str;
+
+    /// `HASH(data)` over the concatenation of `data`.
+    fn hash(data: &[&[u8]]) -> Vec<u8>;
+
+    /// `HMAC-HASH(key, data)` over the concatenation of `data`.
+    fn hmac(key: &[u8], data: &[&[u8]]) -> Vec<u8>;
+}
+
+/// The DH functions of a noise suite.
+///
+/// See [DH functions](https://noiseprotocol.org/noise.html#dh-functions)
+pub trait Dh {
+    /// The number of bytes in a public key and in the output of `DH()`.
+    const DHLEN: usize;
+
+    type PublicKey: Clone;
+    type PrivateKey: Clone;
+
+    /// The name of the DH function as it appears in the protocol name, e.g. `25519`.
+    fn name() -> &'static str;
+
+    /// `GENERATE_KEYPAIR()`
+    fn generate_keypair() -> (Self::PublicKey, Self::PrivateKey);
+
+    /// `DH(key_pair, public_key)`
+    fn dh(private: &Self::PrivateKey, public: &Self::PublicKey) -> Vec<u8>;
+
+    /// Derives the public key belonging to `private`.
+    fn public_key(private: &Self::PrivateKey) -> Self::PublicKey;
+
+    /// Encodes a public key as the `DHLEN` bytes sent in handshake messages.
+    fn public_to_bytes(public: &Self::PublicKey) -> Vec<u8>;
+
+    /// Decodes `DHLEN` bytes received in a handshake message into a public key.
+    fn public_from_bytes(bytes: &[u8]) -> Result<Self::PublicKey, DhError>;
+
+    /// Decodes a private key, used to supply fixed keys rather than generating them.
+    fn private_from_bytes(bytes: &[u8]) -> Result<Self::PrivateKey, DhError>;
+}
+
+/// The `ChaChaPoly` cipher, backed by [chacha20poly1305::ChaCha20Poly1305].
+pub struct ChaChaPoly;
+
+impl Cipher for ChaChaPoly {
+    fn name() -> &'static str {
+        "ChaChaPoly"
+    }
+
+    fn encrypt(k: &[u8], n: u64, ad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, CipherError> {
+        ChaCha20Poly1305::new_from_slice(k)
+            .map_err(|_| CipherError::EncryptionFail())?
+            .encrypt(&chacha_nonce(n).into(), Payload { msg: plaintext, aad: ad })
+            .map_err(|_| CipherError::EncryptionFail())
+    }
+
+    fn decrypt(k: &[u8], n: u64, ad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, CipherError> {
+        ChaCha20Poly1305::new_from_slice(k)
+            .map_err(|_| CipherError::DecryptionFail())?
+            .decrypt(&chacha_nonce(n).into(), Payload { msg: ciphertext, aad: ad })
+            .map_err(|_| CipherError::DecryptionFail())
+    }
+}
+
+/// The `AESGCM` cipher, backed by [aes_gcm::Aes256Gcm].
+pub struct AesGcm;
+
+impl Cipher for AesGcm {
+    fn name() -> &'static str {
+        "AESGCM"
+    }
+
+    fn encrypt(k: &[u8], n: u64, ad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, CipherError> {
+        Aes256Gcm::new_from_slice(k)
+            .map_err(|_| CipherError::EncryptionFail())?
+            .encrypt(&aes_nonce(n).into(), Payload { msg: plaintext, aad: ad })
+            .map_err(|_| CipherError::EncryptionFail())
+    }
+
+    fn decrypt(k: &[u8], n: u64, ad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, CipherError> {
+        Aes256Gcm::new_from_slice(k)
+            .map_err(|_| CipherError::DecryptionFail())?
+            .decrypt(&aes_nonce(n).into(), Payload { msg: ciphertext, aad: ad })
+            .map_err(|_| CipherError::DecryptionFail())
+    }
+}
+
+/// `ChaChaPoly` nonces are 32 bits of zeros followed by the little-endian encoding of `n`.
+fn chacha_nonce(n: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&n.to_le_bytes());
+    nonce
+}
+
+/// `AESGCM` nonces are 32 bits of zeros followed by the big-endian encoding of `n`.
+fn aes_nonce(n: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&n.to_be_bytes());
+    nonce
+}
+
+fn digest_hash<D: Digest>(data: &[&[u8]]) -> Vec<u8> {
+    let mut hasher = D::new();
+    data.iter().for_each(|chunk| hasher.update(chunk));
+    hasher.finalize().to_vec()
+}
+
+fn digest_hmac<D: Digest + BlockSizeUser>(key: &[u8], data: &[&[u8]]) -> Vec<u8> {
+    let mut mac = <SimpleHmac<D> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    data.iter().for_each(|chunk| mac.update(chunk));
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// The `SHA256` hash, backed by [sha2::Sha256].
+pub struct Sha256;
+
+impl Hash for Sha256 {
+    const HASHLEN: usize = 32;
+
+    fn name() -> &'static str {
+        "SHA256"
+    }
+
+    fn hash(data: &[&[u8]]) -> Vec<u8> {
+        digest_hash::<sha2::Sha256>(data)
+    }
+
+    fn hmac(key: &[u8], data: &[&[u8]]) -> Vec<u8> {
+        digest_hmac::<sha2::Sha256>(key, data)
+    }
+}
+
+/// The `SHA512` hash, backed by [sha2::Sha512].
+pub struct Sha512;
+
+impl Hash for Sha512 {
+    const HASHLEN: usize = 64;
+
+    fn name() -> &'static str {
+        "SHA512"
+    }
+
+    fn hash(data: &[&[u8]]) -> Vec<u8> {
+        digest_hash::<sha2::Sha512>(data)
+    }
+
+    fn hmac(key: &[u8], data: &[&[u8]]) -> Vec<u8> {
+        digest_hmac::<sha2::Sha512>(key, data)
+    }
+}
+
+/// The `BLAKE2s` hash, backed by [blake2::Blake2s256].
+pub struct Blake2s;
+
+impl Hash for Blake2s {
+    const HASHLEN: usize = 32;
+
+    fn name() -> &'static str {
+        "BLAKE2s"
+    }
+
+    fn hash(data: &[&[u8]]) -> Vec<u8> {
+        digest_hash::<blake2::Blake2s256>(data)
+    }
+
+    fn hmac(key: &[u8], data: &[&[u8]]) -> Vec<u8> {
+        digest_hmac::<blake2::Blake2s256>(key, data)
+    }
+}
+
+/// The `BLAKE2b` hash, backed by [blake2::Blake2b512].
+pub struct Blake2b;
+
+impl Hash for Blake2b {
+    const HASHLEN: usize = 64;
+
+    fn name() -> &'static str {
+        "BLAKE2b"
+    }
+
+    fn hash(data: &[&[u8]]) -> Vec<u8> {
+        digest_hash::<blake2::Blake2b512>(data)
+    }
+
+    fn hmac(key: &[u8], data: &[&[u8]]) -> Vec<u8> {
+        digest_hmac::<blake2::Blake2b512>(key, data)
+    }
+}
+
+/// The `25519` DH functions, backed by [x25519_dalek].
+pub struct X25519;
+
+impl Dh for X25519 {
+    const DHLEN: usize = 32;
+
+    type PublicKey = x25519_dalek::PublicKey;
+    // x25519-lib reccomends using static for both secrets when using Noise
+    type PrivateKey = x25519_dalek::StaticSecret;
+
+    fn name() -> &'static str {
+        "25519"
+    }
+
+    fn generate_keypair() -> (Self::PublicKey, Self::PrivateKey) {
+        let secret = x25519_dalek::StaticSecret::new(OsRng);
+        (Self::public_key(&secret), secret)
+    }
+
+    fn dh(private: &Self::PrivateKey, public: &Self::PublicKey) -> Vec<u8> {
+        private.diffie_hellman(public).as_bytes().to_vec()
+    }
+
+    fn public_key(private: &Self::PrivateKey) -> Self::PublicKey {
+        x25519_dalek::PublicKey::from(private)
+    }
+
+    fn public_to_bytes(public: &Self::PublicKey) -> Vec<u8> {
+        public.as_bytes().to_vec()
+    }
+
+    fn public_from_bytes(bytes: &[u8]) -> Result<Self::PublicKey, DhError> {
+        let bytes: [u8; 32] = bytes.try_into().map_err(|_| DhError::InvalidPublicKey(Self::DHLEN))?;
+        Ok(x25519_dalek::PublicKey::from(bytes))
+    }
+
+    fn private_from_bytes(bytes: &[u8]) -> Result<Self::PrivateKey, DhError> {
+        let bytes: [u8; 32] = bytes.try_into().map_err(|_| DhError::InvalidPrivateKey(Self::DHLEN))?;
+        Ok(x25519_dalek::StaticSecret::from(bytes))
+    }
+}
+
+/// The `448` DH functions, see [x448] for the underlying curve arithmetic.
+pub struct X448;
+
+impl Dh for X448 {
+    const DHLEN: usize = x448::KEYLEN;
+
+    type PublicKey = [u8; x448::KEYLEN];
+    type PrivateKey = [u8; x448::KEYLEN];
+
+    fn name() -> &'static str {
+        "448"
+    }
+
+    fn generate_keypair() -> (Self::PublicKey, Self::PrivateKey) {
+        let mut secret = [0u8; x448::KEYLEN];
+        OsRng.fill_bytes(&mut secret);
+        (Self::public_key(&secret), secret)
+    }
+
+    fn dh(private: &Self::PrivateKey, public: &Self::PublicKey) -> Vec<u8> {
+        x448::x448(private, public).to_vec()
+    }
+
+    fn public_key(private: &Self::PrivateKey) -> Self::PublicKey {
+        x448::x448_base(private)
+    }
+
+    fn public_to_bytes(public: &Self::PublicKey) -> Vec<u8> {
+        public.to_vec()
+    }
+
+    fn public_from_bytes(bytes: &[u8]) -> Result<Self::PublicKey, DhError> {
+        bytes.try_into().map_err(|_| DhError::InvalidPublicKey(Self::DHLEN))
+    }
+
+    fn private_from_bytes(bytes: &[u8]) -> Result<Self::PrivateKey, DhError> {
+        bytes.try_into().map_err(|_| DhError::InvalidPrivateKey(Self::DHLEN))
+    }
+}
+
+/// Builds the noise protocol name for a handshake pattern and suite, e.g. `Noise_XX_25519_ChaChaPoly_SHA256`.
+pub fn protocol_name<D: Dh, C: Cipher, H: Hash>(pattern: &str) -> String {
+    format!("Noise_{}_{}_{}_{}", pattern, D::name(), C::name(), H::name())
+}
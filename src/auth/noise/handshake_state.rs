@@ -1,201 +1,345 @@
 //use ecies_ed25519 as ecs;
 use super::cipher;
+use super::crypto::{self, ChaChaPoly, Cipher, Dh, Hash, Sha256, X25519};
+use super::pattern::HandshakePattern;
 use super::symmetric_state::SymmetricState;
-use super::{MessagePattern, DHLEN};
+use super::{MessagePattern, PSKLEN};
+use std::collections::VecDeque;
 use std::error::Error;
-use rand::rngs::OsRng;
-use x25519_dalek::{PublicKey, StaticSecret};
 use thiserror::Error;
 
-const PROTOCOL_NAME: &[u8; 32] = b"Noise_XX_25519_ChaChaPoly_SHA256";
-
 #[derive(Error, Debug)]
 pub enum HandshakeError {
     #[error("the message pattern prvided was unsuported")]
     MessagePatternUnsupported(),
+    #[error("the handshake pattern {0} is not defined")]
+    UnknownPattern(String),
+    #[error("a public key required by the pre-message was not supplied")]
+    MissingPreMessageKey(),
+    #[error("all messages of the handshake pattern have been processed")]
+    HandshakeFinished(),
+    #[error("the handshake pattern requires more pre-shared keys than were supplied")]
+    MissingPsk(),
+    #[error("the handshake message is shorter than its tokens require")]
+    MessageTooShort(),
+    #[error("noise messages must not be longer than 65535 bytes")]
+    MessageTooLong(),
+    #[error("a DH token was processed before both of the keys it uses were known")]
+    MissingDhKey(),
+    #[error("the next message of the handshake pattern is not ours to {0}")]
+    OutOfTurn(&'static str),
+    #[error("transport ciphers can only be split once every handshake message has been processed")]
+    HandshakeIncomplete(),
+    #[error("a previous handshake message failed, the handshake must be abandoned")]
+    HandshakeFailed(),
 }
 
-#[derive(Clone)]
-pub struct StaticKeypair(pub PublicKey, pub StaticSecret);
+/// The maximum length of any noise message, see [Message format](https://noiseprotocol.org/noise.html#message-format)
+pub const MAX_MESSAGE_LEN: usize = 65535;
+
+pub struct StaticKeypair<D: Dh = X25519>(pub D::PublicKey, pub D::PrivateKey);
 
-type EphemeralKeypair = (PublicKey, StaticSecret); // x25519-lib reccomends using static for both secrets when using Noise
+pub type EphemeralKeypair<D = X25519> = (<D as Dh>::PublicKey, <D as Dh>::PrivateKey);
 
-impl StaticKeypair {
+impl StaticKeypair<X25519> {
     pub fn new() -> Self {
-        let secret = StaticSecret::new(OsRng);
-        let public = PublicKey::from(&secret);
-        StaticKeypair(public, secret)
+        Self::generate()
+    }
+}
+
+impl<D: Dh> StaticKeypair<D> {
+    /// Generates a static keypair for any [Dh] function, see [StaticKeypair::new] for `25519`.
+    pub fn generate() -> Self {
+        let (public, private) = D::generate_keypair();
+        StaticKeypair(public, private)
+    }
+}
+
+impl<D: Dh> Clone for StaticKeypair<D> {
+    fn clone(&self) -> Self {
+        StaticKeypair(self.0.clone(), self.1.clone())
     }
 }
 
-impl Default for StaticKeypair {
+impl Default for StaticKeypair<X25519> {
     fn default() -> Self {
         Self::new()
     }
 }
 
-pub struct HandshakeState {
+/// An implementation of the `HandshakeState` object, generic over the DH, cipher and hash
+/// functions of the noise suite. The defaults give `25519_ChaChaPoly_SHA256` as used by libp2p.
+///
+/// Messages can only be written and read in the order the pattern gives them, nothing more is
+/// processed once a message has failed and [HandshakeState::finalize] waits for the last message.
+pub struct HandshakeState<D: Dh = X25519, C: Cipher = ChaChaPoly, H: Hash = Sha256> {
     initiator: bool,
-    symmetric_state: SymmetricState,
+    symmetric_state: SymmetricState<C, H>,
+    pattern: HandshakePattern,
+    message_index: usize,
+    failed: bool,
 
-    pub s: StaticKeypair, // local static
-    pub rs: Option<PublicKey>,
-    e: Option<EphemeralKeypair>, // local ephemeral
-    re: Option<PublicKey>,
+    pub s: StaticKeypair<D>, // local static
+    pub rs: Option<D::PublicKey>,
+    e: Option<EphemeralKeypair<D>>, // local ephemeral
+    re: Option<D::PublicKey>,
+    psks: VecDeque<[u8; PSKLEN]>,
+    fixed_e: Option<D::PrivateKey>, // ephemeral for the next `e` token, generated when absent
 }
 
-impl HandshakeState {
+impl<D: Dh, C: Cipher, H: Hash> HandshakeState<D, C, H> {
     /// Calls "Initialize" from the the HandshakeState noise protocol specification:
     ///
+    /// The protocol name is derived from `pattern`, and the public keys of its pre-messages are
+    /// taken from `s`, `e`, `rs` and `re` depending on which side of the handshake we are.
+    ///
     /// See [Initialize](https://noiseprotocol.org/noise.html#the-handshakestate-object)
     pub fn new(
+        pattern: HandshakePattern,
         initiator: bool,
         prologue: &[u8],
-        s: StaticKeypair,
-        e: Option<EphemeralKeypair>,
-        rs: Option<PublicKey>,
-        re: Option<PublicKey>,
-    ) -> HandshakeState {
-        let mut sym_state = SymmetricState::new(PROTOCOL_NAME);
+        s: StaticKeypair<D>,
+        e: Option<EphemeralKeypair<D>>,
+        rs: Option<D::PublicKey>,
+        re: Option<D::PublicKey>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let protocol_name = crypto::protocol_name::<D, C, H>(pattern.name());
+        let mut sym_state = SymmetricState::new(protocol_name.as_bytes());
         sym_state.mix_hash(prologue);
-        HandshakeState {
+        let mut hss = HandshakeState {
             s,
             e,
             rs,
             re,
             initiator,
             symmetric_state: sym_state,
+            pattern,
+            message_index: 0,
+            failed: false,
+            psks: VecDeque::new(),
+            fixed_e: None,
+        };
+        let (initiator_pre, responder_pre) = (
+            hss.pattern.initiator_pre().to_vec(),
+            hss.pattern.responder_pre().to_vec(),
+        );
+        hss.mix_pre_message(&initiator_pre, initiator)?;
+        hss.mix_pre_message(&responder_pre, !initiator)?;
+        Ok(hss)
+    }
+
+    /// Supplies the pre-shared keys consumed, in order, by the `psk` tokens of a pattern such as `XXpsk3`.
+    pub fn with_psks(mut self, psks: Vec<[u8; PSKLEN]>) -> Self {
+        self.psks = psks.into();
+        self
+    }
+
+    /// Supplies the private key written by our `e` token instead of generating one, so a handshake can be
+    /// replayed against test vectors. Reusing an ephemeral key across handshakes forfeits forward secrecy.
+    pub fn with_ephemeral(mut self, private: D::PrivateKey) -> Self {
+        self.fixed_e = Some(private);
+        self
+    }
+
+    /// Mixes the next pre-shared key into the handshake when a `psk` token is processed.
+    fn mix_psk(&mut self) -> Result<(), HandshakeError> {
+        let psk = self.psks.pop_front().ok_or(HandshakeError::MissingPsk())?;
+        self.symmetric_state.mix_key_and_hash(&psk);
+        Ok(())
+    }
+
+    /// Hashes the public keys of a pre-message, `local` is true if the pre-message was sent by us.
+    /// Like an `e` token, an ephemeral pre-message is also mixed into the key of a `psk` handshake.
+    fn mix_pre_message(&mut self, patterns: &[MessagePattern], local: bool) -> Result<(), Box<dyn Error>> {
+        for pattern in patterns {
+            let public = match (pattern, local) {
+                (MessagePattern::S, true) => Some(&self.s.0),
+                (MessagePattern::S, false) => self.rs.as_ref(),
+                (MessagePattern::E, true) => self.e.as_ref().map(|e| &e.0),
+                (MessagePattern::E, false) => self.re.as_ref(),
+                _ => return Err(HandshakeError::MessagePatternUnsupported().into()),
+            };
+            let public = public.ok_or(HandshakeError::MissingPreMessageKey())?;
+            let public = D::public_to_bytes(public);
+            self.symmetric_state.mix_hash(&public);
+            if *pattern == MessagePattern::E && self.pattern.is_psk() {
+                self.symmetric_state.mix_key(&public);
+            }
         }
+        Ok(())
+    }
+
+    /// Performs the DH for a `ee`, `es`, `se` or `ss` token and mixes the result into the chaining key,
+    /// `local_static`/`remote_static` select which of our and their keys the token refers to.
+    fn mix_dh(&mut self, local_static: bool, remote_static: bool) -> Result<(), HandshakeError> {
+        let private = if local_static { Some(&self.s.1) } else { self.e.as_ref().map(|e| &e.1) };
+        let public = if remote_static { self.rs.as_ref() } else { self.re.as_ref() };
+        let (private, public) = private.zip(public).ok_or(HandshakeError::MissingDhKey())?;
+        let shared = D::dh(private, public);
+        self.symmetric_state.mix_key(&shared);
+        Ok(())
     }
 
-    /// Calls "WriteMessage" from the the HandshakeState noise protocol specification:
+    /// True once every message of the handshake pattern has been written or read.
+    pub fn is_finished(&self) -> bool {
+        self.message_index >= self.pattern.messages().len()
+    }
+
+    /// True if the next message of the handshake pattern is ours to write.
+    pub fn is_my_turn(&self) -> bool {
+        self.message_index.is_multiple_of(2) == self.initiator
+    }
+
+    /// Rejects a message that cannot be processed in the current state, `write` selects whether
+    /// the caller wants to write the next message or read it.
+    fn check_turn(&self, write: bool) -> Result<(), HandshakeError> {
+        if self.failed {
+            return Err(HandshakeError::HandshakeFailed());
+        }
+        if self.is_finished() {
+            return Err(HandshakeError::HandshakeFinished());
+        }
+        if self.is_my_turn() != write {
+            return Err(HandshakeError::OutOfTurn(if write { "write" } else { "read" }));
+        }
+        Ok(())
+    }
+
+    /// Takes the message patterns of the next handshake message and advances the pattern.
+    fn next_patterns(&mut self) -> Result<Vec<MessagePattern>, HandshakeError> {
+        let patterns = self
+            .pattern
+            .messages()
+            .get(self.message_index)
+            .cloned()
+            .ok_or(HandshakeError::HandshakeFinished())?;
+        self.message_index += 1;
+        Ok(patterns)
+    }
+
+    /// Calls "WriteMessage" from the the HandshakeState noise protocol specification,
+    /// the message patterns are taken from the next message of the handshake pattern.
     ///
     /// See [WriteMessage](https://noiseprotocol.org/noise.html#the-handshakestate-object)
-    pub fn write_message(&mut self, payload: &[u8], patterns: Vec<MessagePattern>) -> Result<Vec<u8>, Box<dyn Error>> {
+    pub fn write_message(&mut self, payload: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.check_turn(true)?;
+        let result = self.write_tokens(payload);
+        self.failed = result.is_err();
+        result
+    }
+
+    fn write_tokens(&mut self, payload: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let patterns = self.next_patterns()?;
         let mut buffer: Vec<u8> = Vec::new();
         for pattern in patterns {
             match pattern {
                 MessagePattern::E => {
-                    let secret = StaticSecret::new(OsRng);
-                    let public = PublicKey::from(&secret);
+                    let (public, secret) = match self.fixed_e.take() {
+                        Some(secret) => (D::public_key(&secret), secret),
+                        None => D::generate_keypair(),
+                    };
+                    let public_bytes = D::public_to_bytes(&public);
                     self.e = Some((public, secret));
-                    let public_bytes = public.as_bytes().to_vec();
                     let mut buf_bytes = public_bytes.clone();
                     buffer.append(&mut buf_bytes);
                     self.symmetric_state.mix_hash(&public_bytes);
-                }
-                MessagePattern::Ee => {
-                    self.symmetric_state.mix_key(&cipher::dh_static(
-                        &self.e.as_ref().unwrap().1,
-                        &self.re.unwrap(),
-                    ));
-                }
-                MessagePattern::Es => {
-                    if self.initiator {
-                        self.symmetric_state.mix_key(&cipher::dh_static(
-                            &self.e.as_ref().unwrap().1,
-                            &self.rs.unwrap(),
-                        ));
-                    } else {
-                        self.symmetric_state
-                            .mix_key(&cipher::dh_static(&self.s.1, &self.re.unwrap()));
+                    if self.pattern.is_psk() {
+                        self.symmetric_state.mix_key(&public_bytes);
                     }
                 }
+                MessagePattern::Ee => self.mix_dh(false, false)?,
+                MessagePattern::Es => self.mix_dh(!self.initiator, self.initiator)?,
                 MessagePattern::S => {
-                    let mut payload = self.symmetric_state.encrypt_and_hash(self.s.0.as_bytes())?;
+                    let mut payload = self.symmetric_state.encrypt_and_hash(&D::public_to_bytes(&self.s.0))?;
                     buffer.append(&mut payload);
                 }
-                MessagePattern::Se => {
-                    if self.initiator {
-                        self.symmetric_state
-                            .mix_key(&cipher::dh_static(&self.s.1, &self.re.unwrap()));
-                    } else {
-                        self.symmetric_state.mix_key(&cipher::dh_static(
-                            &self.e.as_ref().unwrap().1,
-                            &self.rs.unwrap(),
-                        ));
-                    }
-                }
-                _ => return Err(HandshakeError::MessagePatternUnsupported().into()),
+                MessagePattern::Se => self.mix_dh(self.initiator, !self.initiator)?,
+                MessagePattern::Ss => self.mix_dh(true, true)?,
+                MessagePattern::Psk => self.mix_psk()?,
             }
         }
 
         let mut encrypted = self.symmetric_state.encrypt_and_hash(payload)?;
         buffer.append(&mut encrypted);
+        if buffer.len() > MAX_MESSAGE_LEN {
+            return Err(HandshakeError::MessageTooLong().into());
+        }
         Ok(buffer)
     }
 
-    /// Calls "ReadMessage" from the the HandshakeState noise protocol specification:
+    /// Calls "ReadMessage" from the the HandshakeState noise protocol specification,
+    /// the message patterns are taken from the next message of the handshake pattern.
     ///
     /// [ReadMessage](https://noiseprotocol.org/noise.html#the-handshakestate-object)
-    pub fn read_message(&mut self, received: &[u8], patterns: Vec<MessagePattern>) -> Result<Vec<u8>, Box<dyn Error>> {
-        let mut received = received.to_vec();
+    ///
+    /// Messages that are too long, too short for their tokens or fail to decrypt return an error
+    /// rather than panicking, the handshake must be abandoned after any error.
+    pub fn read_message(&mut self, received: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.check_turn(false)?;
+        let result = self.read_tokens(received);
+        self.failed = result.is_err();
+        result
+    }
+
+    fn read_tokens(&mut self, received: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        if received.len() > MAX_MESSAGE_LEN {
+            return Err(HandshakeError::MessageTooLong().into());
+        }
+        let patterns = self.next_patterns()?;
+        let mut received = received;
         for pattern in patterns {
             match pattern {
                 MessagePattern::E => {
-                    let mut remote_public = [0u8; DHLEN];
-                    received[..DHLEN]
-                        .iter()
-                        .enumerate()
-                        .for_each(|(index, val)| {
-                            remote_public[index] = *val;
-                        });
-                    let public = PublicKey::from(remote_public);
-                    self.re = Some(public);
-                    self.symmetric_state.mix_hash(&remote_public);
-                    received.drain(..DHLEN);
-                }
-                MessagePattern::Ee => {
-                    self.symmetric_state.mix_key(&cipher::dh_static(
-                        &self.e.as_ref().unwrap().1,
-                        &self.re.unwrap(),
-                    ));
+                    let remote_public = Self::take(&mut received, D::DHLEN)?;
+                    self.re = Some(D::public_from_bytes(remote_public)?);
+                    self.symmetric_state.mix_hash(remote_public);
+                    if self.pattern.is_psk() {
+                        self.symmetric_state.mix_key(remote_public);
+                    }
                 }
+                MessagePattern::Ee => self.mix_dh(false, false)?,
                 MessagePattern::S => {
                     let has_key = self.symmetric_state.cipher_state.has_key();
-                    let window = if has_key { ..DHLEN + 16 } else { ..DHLEN };
-                    let temp = &received[window];
-                    let mut remote_static_bytes: [u8; DHLEN] = [0; DHLEN];
-                    self.symmetric_state
-                        .decrypt_and_hash(temp)?
-                        .iter()
-                        .enumerate()
-                        .for_each(|(index, val)| {
-                            remote_static_bytes[index] = *val;
-                        });
-                    self.rs = Some(PublicKey::from(remote_static_bytes));
-                    received.drain(window);
-                }
-                MessagePattern::Es => {
-                    if self.initiator {
-                        self.symmetric_state.mix_key(&cipher::dh_static(
-                            &self.e.as_ref().unwrap().1,
-                            &self.rs.unwrap(),
-                        ));
-                    } else {
-                        self.symmetric_state
-                            .mix_key(&cipher::dh_static(&self.s.1, &self.re.unwrap()));
-                    }
+                    let len = if has_key { D::DHLEN + 16 } else { D::DHLEN };
+                    let temp = Self::take(&mut received, len)?;
+                    let remote_static_bytes = self.symmetric_state.decrypt_and_hash(temp)?;
+                    self.rs = Some(D::public_from_bytes(&remote_static_bytes)?);
                 }
-                MessagePattern::Se => {
-                    if self.initiator {
-                        self.symmetric_state
-                            .mix_key(&cipher::dh_static(&self.s.1, &self.re.unwrap()));
-                    } else {
-                        self.symmetric_state.mix_key(&cipher::dh_static(
-                            &self.e.as_ref().unwrap().1,
-                            &self.rs.unwrap(),
-                        ));
-                    }
-                }
-                _ => return Err(HandshakeError::MessagePatternUnsupported().into())
+                MessagePattern::Es => self.mix_dh(!self.initiator, self.initiator)?,
+                MessagePattern::Se => self.mix_dh(self.initiator, !self.initiator)?,
+                MessagePattern::Ss => self.mix_dh(true, true)?,
+                MessagePattern::Psk => self.mix_psk()?,
             }
         }
-        self.symmetric_state.decrypt_and_hash(&received)
+        self.symmetric_state.decrypt_and_hash(received)
+    }
+
+    /// Splits the first `len` bytes off the front of a received message.
+    fn take<'m>(received: &mut &'m [u8], len: usize) -> Result<&'m [u8], HandshakeError> {
+        if received.len() < len {
+            return Err(HandshakeError::MessageTooShort());
+        }
+        let (token, rest) = received.split_at(len);
+        *received = rest;
+        Ok(token)
     }
 
-    /// A helper function used to produce two [cipher::CipherState] which encrypt and decrypt messages on the transport.
-    pub fn finalize(&self) -> (cipher::CipherState, cipher::CipherState) {
-        self.symmetric_state.split()
+    /// The handshake hash `h`, both peers hold the same value once the handshake is finished.
+    ///
+    /// See [Channel binding](https://noiseprotocol.org/noise.html#channel-binding)
+    pub fn handshake_hash(&self) -> &[u8] {
+        self.symmetric_state.handshake_hash()
+    }
+
+    /// A helper function used to produce two [cipher::CipherState] which encrypt and decrypt messages on the transport,
+    /// fails with [HandshakeError::HandshakeIncomplete] until the last message of the pattern has been processed.
+    pub fn finalize(&self) -> Result<(cipher::CipherState<C>, cipher::CipherState<C>), HandshakeError> {
+        if self.failed {
+            return Err(HandshakeError::HandshakeFailed());
+        }
+        if !self.is_finished() {
+            return Err(HandshakeError::HandshakeIncomplete());
+        }
+        Ok(self.symmetric_state.split())
     }
 }
@@ -1,15 +1,18 @@
 use ed25519_dalek::SecretKey;
 use x25519_dalek::{EphemeralSecret, StaticSecret};
 
-mod cipher;
+pub mod cipher;
+pub mod crypto;
 mod symmetric_state;
 pub mod handshake_state;
+pub mod pattern;
 pub mod protocol;
+mod x448;
 
-// number of bytes resultant from a SHA256 hash
-pub const DHLEN: usize = 32;
-pub const HASHLEN: usize = 32;
+// number of bytes in a pre-shared key
+pub const PSKLEN: usize = 32;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum MessagePattern {
     E,
     S,
@@ -17,6 +20,7 @@ pub enum MessagePattern {
     Es,
     Se,
     Ss,
+    Psk,
 }
 
 pub enum PrivateKeyType<'a> {
@@ -31,51 +35,278 @@ mod tests {
     fn test_xx() {
         use crate::auth::noise::{
             handshake_state::{HandshakeState, StaticKeypair},
-            MessagePattern,
+            pattern::HandshakePattern,
         };
 
         let static_local = StaticKeypair::new();
         let static_remote = StaticKeypair::new();
 
-        let mut hss_local = HandshakeState::new(true, &[], static_local, None, None, None);
-        let mut hss_remote = HandshakeState::new(false, &[], static_remote, None, None, None);
+        let mut hss_local: HandshakeState =
+            HandshakeState::new(HandshakePattern::xx(), true, &[], static_local, None, None, None).unwrap();
+        let mut hss_remote: HandshakeState =
+            HandshakeState::new(HandshakePattern::xx(), false, &[], static_remote, None, None, None).unwrap();
 
         // Write from local
         println!("Local Write -> [e] -> Remote");
-        let stage1 = hss_local.write_message(&[], vec![MessagePattern::E]).unwrap();
+        let stage1 = hss_local.write_message(&[]).unwrap();
 
         // Read and write from remote
         println!("Remote Read: [e]");
-        hss_remote.read_message(&stage1, vec![MessagePattern::E]).unwrap();
+        hss_remote.read_message(&stage1).unwrap();
         println!("Remote Write -> [e, ee, s, es] -> Local");
-        let respond = hss_remote.write_message(
-            b"2nd stage",
-            vec![
-                MessagePattern::E,
-                MessagePattern::Ee,
-                MessagePattern::S,
-                MessagePattern::Es,
-            ],
-        ).unwrap();
+        let respond = hss_remote.write_message(b"2nd stage").unwrap();
         // Read from local
         println!("Local Read: [e, ee, s, es]");
-        hss_local.read_message(
-            &respond,
-            vec![
-                MessagePattern::E,
-                MessagePattern::Ee,
-                MessagePattern::S,
-                MessagePattern::Es,
-            ],
-        ).unwrap();
+        hss_local.read_message(&respond).unwrap();
         // Write back to remote
         println!("Local Write -> [s, se] -> Remote");
-        let stage3 =
-            hss_local.write_message(b"3rd stage", vec![MessagePattern::S, MessagePattern::Se]).unwrap();
+        let stage3 = hss_local.write_message(b"3rd stage").unwrap();
         println!("Remote Read: [s, se]");
-        let final_resp =
-            hss_remote.read_message(&stage3, vec![MessagePattern::S, MessagePattern::Se]).unwrap();
+        let final_resp = hss_remote.read_message(&stage3).unwrap();
 
         println!("output: {}", std::str::from_utf8(&final_resp).unwrap());
     }
+
+    #[test]
+    fn test_patterns() {
+        use crate::auth::noise::{
+            handshake_state::{HandshakeState, StaticKeypair},
+            pattern::HandshakePattern,
+            MessagePattern,
+        };
+
+        for name in [
+            "N", "K", "X", "NN", "NK", "NX", "XN", "XK", "XX", "KN", "KK", "KX", "IN", "IK", "IX",
+        ] {
+            let pattern = HandshakePattern::new(name).unwrap();
+            let static_local = StaticKeypair::new();
+            let static_remote = StaticKeypair::new();
+
+            // Pre-messages are known to the other party ahead of time
+            let local_rs = pattern.responder_pre().contains(&MessagePattern::S).then_some(static_remote.0);
+            let remote_rs = pattern.initiator_pre().contains(&MessagePattern::S).then_some(static_local.0);
+            let mut hss_local: HandshakeState =
+                HandshakeState::new(pattern.clone(), true, b"prologue", static_local, None, local_rs, None).unwrap();
+            let mut hss_remote: HandshakeState =
+                HandshakeState::new(pattern, false, b"prologue", static_remote, None, remote_rs, None).unwrap();
+
+            let (mut writer, mut reader) = (&mut hss_local, &mut hss_remote);
+            while !writer.is_finished() {
+                assert!(writer.is_my_turn() && !reader.is_my_turn());
+                let message = writer.write_message(name.as_bytes()).unwrap();
+                assert_eq!(reader.read_message(&message).unwrap(), name.as_bytes());
+                std::mem::swap(&mut writer, &mut reader);
+            }
+
+            assert_eq!(hss_local.handshake_hash(), hss_remote.handshake_hash());
+            let (mut local_encrypter, _) = hss_local.finalize().unwrap();
+            let (mut remote_decrypter, _) = hss_remote.finalize().unwrap();
+            let ciphertext = local_encrypter.encrypt_with_ad(&[], b"transport").unwrap();
+            assert_eq!(remote_decrypter.decrypt_with_ad(&[], &ciphertext).unwrap(), b"transport");
+        }
+    }
+
+    #[test]
+    fn test_psk_patterns() {
+        use crate::auth::noise::{
+            handshake_state::{HandshakeState, StaticKeypair},
+            pattern::HandshakePattern,
+        };
+
+        for (name, psks) in [("NNpsk0", 1), ("XXpsk3", 1), ("IKpsk2", 1), ("NNpsk0+psk2", 2)] {
+            let pattern = HandshakePattern::new(name).unwrap();
+            assert!(pattern.is_psk());
+            let static_local = StaticKeypair::new();
+            let static_remote = StaticKeypair::new();
+            let local_rs = (name == "IKpsk2").then_some(static_remote.0);
+            let local_psks = vec![[7u8; 32]; psks];
+
+            // A responder with the wrong key must fail to read the first encrypted payload
+            for (remote_psks, matching) in [(local_psks.clone(), true), (vec![[8u8; 32]; psks], false)] {
+                let mut hss_local: HandshakeState =
+                    HandshakeState::new(pattern.clone(), true, &[], static_local.clone(), None, local_rs, None)
+                        .unwrap()
+                        .with_psks(local_psks.clone());
+                let mut hss_remote: HandshakeState =
+                    HandshakeState::new(pattern.clone(), false, &[], static_remote.clone(), None, None, None)
+                        .unwrap()
+                        .with_psks(remote_psks);
+
+                let (mut writer, mut reader) = (&mut hss_local, &mut hss_remote);
+                let mut result = Ok(vec![]);
+                while !writer.is_finished() && result.is_ok() {
+                    let message = writer.write_message(name.as_bytes()).unwrap();
+                    result = reader.read_message(&message);
+                    std::mem::swap(&mut writer, &mut reader);
+                }
+                assert_eq!(result.is_ok(), matching, "{name}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_psk_ephemeral_pre_message() {
+        use crate::auth::noise::{
+            crypto::{Dh, X25519},
+            handshake_state::{HandshakeState, StaticKeypair},
+            pattern::HandshakePattern,
+            MessagePattern::{Psk, E, S},
+        };
+
+        // Only in psk mode does the ephemeral pre-message set a key, so `s` is then sent encrypted
+        for (pattern, message_len) in [
+            (HandshakePattern::custom("Xpsk1", &[E], &[], &[&[S, Psk]]), 32 + 16 + 16),
+            (HandshakePattern::custom("X", &[E], &[], &[&[S]]), 32),
+        ] {
+            let ephemeral = X25519::generate_keypair();
+            let re = Some(ephemeral.0);
+            let mut hss_local: HandshakeState =
+                HandshakeState::new(pattern.clone(), true, &[], StaticKeypair::new(), Some(ephemeral), None, None)
+                    .unwrap()
+                    .with_psks(vec![[7u8; 32]]);
+            let mut hss_remote: HandshakeState =
+                HandshakeState::new(pattern, false, &[], StaticKeypair::new(), None, None, re)
+                    .unwrap()
+                    .with_psks(vec![[7u8; 32]]);
+
+            let message = hss_local.write_message(&[]).unwrap();
+            assert_eq!(message.len(), message_len);
+            assert!(hss_remote.read_message(&message).is_ok());
+            assert_eq!(hss_local.handshake_hash(), hss_remote.handshake_hash());
+        }
+    }
+
+    #[test]
+    fn test_suites() {
+        use crate::auth::noise::{
+            crypto::{self, AesGcm, Blake2b, Blake2s, ChaChaPoly, Cipher, Hash, Sha512, X25519},
+            handshake_state::{HandshakeState, StaticKeypair},
+            pattern::HandshakePattern,
+        };
+
+        fn handshake<C: Cipher, H: Hash>() {
+            let name = crypto::protocol_name::<X25519, C, H>("XX");
+            let mut hss_local = HandshakeState::<X25519, C, H>::new(
+                HandshakePattern::xx(), true, &[], StaticKeypair::generate(), None, None, None,
+            ).unwrap();
+            let mut hss_remote = HandshakeState::<X25519, C, H>::new(
+                HandshakePattern::xx(), false, &[], StaticKeypair::generate(), None, None, None,
+            ).unwrap();
+
+            let (mut writer, mut reader) = (&mut hss_local, &mut hss_remote);
+            while !writer.is_finished() {
+                let message = writer.write_message(name.as_bytes()).unwrap();
+                assert_eq!(reader.read_message(&message).unwrap(), name.as_bytes(), "{name}");
+                std::mem::swap(&mut writer, &mut reader);
+            }
+
+            let (_, mut local_decrypter) = hss_local.finalize().unwrap();
+            let (_, mut remote_encrypter) = hss_remote.finalize().unwrap();
+            let ciphertext = remote_encrypter.encrypt_with_ad(&[], b"transport").unwrap();
+            assert_eq!(local_decrypter.decrypt_with_ad(&[], &ciphertext).unwrap(), b"transport", "{name}");
+        }
+
+        handshake::<AesGcm, Sha512>();
+        handshake::<ChaChaPoly, Blake2s>();
+        handshake::<AesGcm, Blake2b>();
+    }
+
+    #[test]
+    fn test_448_patterns() {
+        use crate::auth::noise::{
+            crypto::{ChaChaPoly, Sha512, X448},
+            handshake_state::{HandshakeState, StaticKeypair},
+            pattern::HandshakePattern,
+        };
+
+        // IK covers a pre-message key as well as `e` and `s` tokens of the larger DH length
+        for name in ["XX", "IK"] {
+            let pattern = HandshakePattern::new(name).unwrap();
+            let static_local = StaticKeypair::<X448>::generate();
+            let static_remote = StaticKeypair::<X448>::generate();
+            let local_rs = (name == "IK").then_some(static_remote.0);
+            let mut hss_local = HandshakeState::<X448, ChaChaPoly, Sha512>::new(
+                pattern.clone(), true, &[], static_local, None, local_rs, None,
+            ).unwrap();
+            let mut hss_remote = HandshakeState::<X448, ChaChaPoly, Sha512>::new(
+                pattern, false, &[], static_remote, None, None, None,
+            ).unwrap();
+
+            let (mut writer, mut reader) = (&mut hss_local, &mut hss_remote);
+            while !writer.is_finished() {
+                let message = writer.write_message(name.as_bytes()).unwrap();
+                assert_eq!(reader.read_message(&message).unwrap(), name.as_bytes());
+                std::mem::swap(&mut writer, &mut reader);
+            }
+            assert_eq!(hss_remote.rs, Some(hss_local.s.0));
+        }
+    }
+
+    #[test]
+    fn test_truncated_messages() {
+        use crate::auth::noise::{
+            handshake_state::{HandshakeError, HandshakeState, StaticKeypair, MAX_MESSAGE_LEN},
+            pattern::HandshakePattern,
+        };
+
+        let handshake = || -> (HandshakeState, HandshakeState) {
+            let hss_local = HandshakeState::new(HandshakePattern::xx(), true, &[], StaticKeypair::new(), None, None, None);
+            let hss_remote = HandshakeState::new(HandshakePattern::xx(), false, &[], StaticKeypair::new(), None, None, None);
+            (hss_local.unwrap(), hss_remote.unwrap())
+        };
+
+        // `e` needs the full 32 bytes of the ephemeral key
+        let (_, mut hss_remote) = handshake();
+        let error = hss_remote.read_message(&[0; 31]).err().unwrap();
+        assert!(matches!(error.downcast_ref(), Some(HandshakeError::MessageTooShort())));
+        let (_, mut hss_remote) = handshake();
+        let error = hss_remote.read_message(&vec![0; MAX_MESSAGE_LEN + 1]).err().unwrap();
+        assert!(matches!(error.downcast_ref(), Some(HandshakeError::MessageTooLong())));
+
+        // Every truncation of [e, ee, s, es] fails on the missing bytes or the authentication tags
+        let (mut hss_local, mut hss_remote) = handshake();
+        hss_remote.read_message(&hss_local.write_message(&[]).unwrap()).unwrap();
+        let message = hss_remote.write_message(&[]).unwrap();
+        for len in 0..message.len() {
+            let (mut hss_local, mut hss_remote) = handshake();
+            hss_remote.read_message(&hss_local.write_message(&[]).unwrap()).unwrap();
+            let message = hss_remote.write_message(&[]).unwrap();
+            assert!(hss_local.read_message(&message[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_turn_order() {
+        use crate::auth::noise::{
+            handshake_state::{HandshakeError, HandshakeState, StaticKeypair},
+            pattern::HandshakePattern,
+        };
+
+        let hss_local = HandshakeState::new(HandshakePattern::xx(), true, &[], StaticKeypair::new(), None, None, None);
+        let hss_remote = HandshakeState::new(HandshakePattern::xx(), false, &[], StaticKeypair::new(), None, None, None);
+        let (mut hss_local, mut hss_remote): (HandshakeState, HandshakeState) =
+            (hss_local.unwrap(), hss_remote.unwrap());
+        let fails_with = |result: Result<Vec<u8>, Box<dyn std::error::Error>>, expected: fn(&HandshakeError) -> bool| {
+            result.err().and_then(|error| error.downcast_ref().map(expected)).unwrap_or(false)
+        };
+        let out_of_turn = |error: &HandshakeError| matches!(error, HandshakeError::OutOfTurn(_));
+
+        // The responder cannot speak first and the initiator cannot read before it has written
+        assert!(fails_with(hss_remote.write_message(&[]), out_of_turn));
+        assert!(fails_with(hss_local.read_message(&[0; 32]), out_of_turn));
+        assert!(matches!(hss_local.finalize(), Err(HandshakeError::HandshakeIncomplete())));
+
+        let message = hss_local.write_message(&[]).unwrap();
+        assert!(fails_with(hss_local.write_message(&[]), out_of_turn));
+        hss_remote.read_message(&message).unwrap();
+
+        // A failed read poisons the state, even when the genuine message arrives afterwards
+        let message = hss_remote.write_message(&[]).unwrap();
+        let mut tampered = message.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(hss_local.read_message(&tampered).is_err());
+        let failed = |error: &HandshakeError| matches!(error, HandshakeError::HandshakeFailed());
+        assert!(fails_with(hss_local.read_message(&message), failed));
+        assert!(matches!(hss_local.finalize(), Err(HandshakeError::HandshakeFailed())));
+    }
 }
@@ -0,0 +1,206 @@
+use super::handshake_state::HandshakeError;
+use super::MessagePattern::{self, E, Ee, Es, Psk, S, Se, Ss};
+
+/// A row in the handshake pattern table, see [HandshakePattern].
+struct PatternDefinition {
+    name: &'static str,
+    initiator_pre: &'static [MessagePattern],
+    responder_pre: &'static [MessagePattern],
+    messages: &'static [&'static [MessagePattern]],
+}
+
+/// The one-way and interactive handshake patterns defined by the noise protocol.
+///
+/// See [Handshake patterns](https://noiseprotocol.org/noise.html#handshake-patterns)
+const PATTERNS: &[PatternDefinition] = &[
+    PatternDefinition {
+        name: "N",
+        initiator_pre: &[],
+        responder_pre: &[S],
+        messages: &[&[E, Es]],
+    },
+    PatternDefinition {
+        name: "K",
+        initiator_pre: &[S],
+        responder_pre: &[S],
+        messages: &[&[E, Es, Ss]],
+    },
+    PatternDefinition {
+        name: "X",
+        initiator_pre: &[],
+        responder_pre: &[S],
+        messages: &[&[E, Es, S, Ss]],
+    },
+    PatternDefinition {
+        name: "NN",
+        initiator_pre: &[],
+        responder_pre: &[],
+        messages: &[&[E], &[E, Ee]],
+    },
+    PatternDefinition {
+        name: "NK",
+        initiator_pre: &[],
+        responder_pre: &[S],
+        messages: &[&[E, Es], &[E, Ee]],
+    },
+    PatternDefinition {
+        name: "NX",
+        initiator_pre: &[],
+        responder_pre: &[],
+        messages: &[&[E], &[E, Ee, S, Es]],
+    },
+    PatternDefinition {
+        name: "XN",
+        initiator_pre: &[],
+        responder_pre: &[],
+        messages: &[&[E], &[E, Ee], &[S, Se]],
+    },
+    PatternDefinition {
+        name: "XK",
+        initiator_pre: &[],
+        responder_pre: &[S],
+        messages: &[&[E, Es], &[E, Ee], &[S, Se]],
+    },
+    PatternDefinition {
+        name: "XX",
+        initiator_pre: &[],
+        responder_pre: &[],
+        messages: &[&[E], &[E, Ee, S, Es], &[S, Se]],
+    },
+    PatternDefinition {
+        name: "KN",
+        initiator_pre: &[S],
+        responder_pre: &[],
+        messages: &[&[E], &[E, Ee, Se]],
+    },
+    PatternDefinition {
+        name: "KK",
+        initiator_pre: &[S],
+        responder_pre: &[S],
+        messages: &[&[E, Es, Ss], &[E, Ee, Se]],
+    },
+    PatternDefinition {
+        name: "KX",
+        initiator_pre: &[S],
+        responder_pre: &[],
+        messages: &[&[E], &[E, Ee, Se, S, Es]],
+    },
+    PatternDefinition {
+        name: "IN",
+        initiator_pre: &[],
+        responder_pre: &[],
+        messages: &[&[E, S], &[E, Ee, Se]],
+    },
+    PatternDefinition {
+        name: "IK",
+        initiator_pre: &[],
+        responder_pre: &[S],
+        messages: &[&[E, Es, S, Ss], &[E, Ee, Se]],
+    },
+    PatternDefinition {
+        name: "IX",
+        initiator_pre: &[],
+        responder_pre: &[],
+        messages: &[&[E, S], &[E, Ee, Se, S, Es]],
+    },
+];
+
+/// A noise handshake pattern: the pre-messages known ahead of time by each party
+/// and the sequence of message patterns exchanged, starting with the initiator.
+///
+/// See [Handshake patterns](https://noiseprotocol.org/noise.html#handshake-patterns)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HandshakePattern {
+    name: String,
+    initiator_pre: Vec<MessagePattern>,
+    responder_pre: Vec<MessagePattern>,
+    messages: Vec<Vec<MessagePattern>>,
+}
+
+impl HandshakePattern {
+    /// Looks up a pattern by its name in the pattern table, e.g. `"XX"` or `"IK"`.
+    ///
+    /// `psk` modifiers may follow the name, e.g. `"XXpsk3"` or `"NNpsk0+psk2"`.
+    /// See [Pattern modifiers](https://noiseprotocol.org/noise.html#pattern-modifiers)
+    pub fn new(name: &str) -> Result<Self, HandshakeError> {
+        let unknown = || HandshakeError::UnknownPattern(name.to_owned());
+        let base_len = name.find(|c: char| c.is_ascii_lowercase()).unwrap_or(name.len());
+        let (base, modifiers) = name.split_at(base_len);
+        let definition = PATTERNS
+            .iter()
+            .find(|definition| definition.name == base)
+            .ok_or_else(unknown)?;
+        let mut pattern = HandshakePattern {
+            name: name.to_owned(),
+            initiator_pre: definition.initiator_pre.to_vec(),
+            responder_pre: definition.responder_pre.to_vec(),
+            messages: definition
+                .messages
+                .iter()
+                .map(|message| message.to_vec())
+                .collect(),
+        };
+        if !modifiers.is_empty() {
+            for modifier in modifiers.split('+') {
+                let index: usize = modifier
+                    .strip_prefix("psk")
+                    .and_then(|index| index.parse().ok())
+                    .ok_or_else(unknown)?;
+                // psk0 starts the first message, pskN ends the Nth message
+                if index == 0 {
+                    pattern.messages[0].insert(0, Psk);
+                } else {
+                    pattern.messages.get_mut(index - 1).ok_or_else(unknown)?.push(Psk);
+                }
+            }
+        }
+        Ok(pattern)
+    }
+
+    /// A pattern that is not in the pattern table, such as one with an ephemeral pre-message.
+    #[cfg(test)]
+    pub(crate) fn custom(
+        name: &str,
+        initiator_pre: &[MessagePattern],
+        responder_pre: &[MessagePattern],
+        messages: &[&[MessagePattern]],
+    ) -> Self {
+        HandshakePattern {
+            name: name.to_owned(),
+            initiator_pre: initiator_pre.to_vec(),
+            responder_pre: responder_pre.to_vec(),
+            messages: messages.iter().map(|message| message.to_vec()).collect(),
+        }
+    }
+
+    /// The `XX` pattern used by libp2p.
+    pub fn xx() -> Self {
+        Self::new("XX").expect("XX is in the pattern table")
+    }
+
+    /// The name of the pattern as it appears in the protocol name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// True if the pattern carries a `psk` modifier, in which case `e` tokens also call `MixKey`.
+    pub fn is_psk(&self) -> bool {
+        self.messages.iter().flatten().any(|pattern| *pattern == Psk)
+    }
+
+    /// The pre-message sent by the initiator before the handshake begins.
+    pub fn initiator_pre(&self) -> &[MessagePattern] {
+        &self.initiator_pre
+    }
+
+    /// The pre-message sent by the responder before the handshake begins.
+    pub fn responder_pre(&self) -> &[MessagePattern] {
+        &self.responder_pre
+    }
+
+    /// The message patterns of the handshake, even indexes are written by the initiator
+    /// and odd indexes by the responder.
+    pub fn messages(&self) -> &[Vec<MessagePattern>] {
+        &self.messages
+    }
+}
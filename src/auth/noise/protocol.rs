@@ -1,30 +1,181 @@
-use ed25519_dalek::{Keypair, PublicKey, Signature, Signer, Verifier};
 use prost::Message;
+use thiserror::Error;
 
 use crate::{
-    auth::{noise::MessagePattern, HandShake, SecureChannel},
-    connection::Connection,
+    auth::{AsyncHandShake, AsyncSecureChannel, HandShake, SecureChannel},
+    connection::{AsyncConnection, Connection},
     handshake,
+    identity::{IdentityError, Keypair, PublicKey},
+    muxer::MuxerProtocol,
+    peer_id::PeerId,
 };
 
 use super::{
     cipher::CipherState,
-    handshake_state::{HandshakeState, StaticKeypair},
+    handshake_state::{HandshakeError, HandshakeState, StaticKeypair, MAX_MESSAGE_LEN},
+    pattern::HandshakePattern,
 };
 
 use std::error::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 const SIGNATURE_PREFIX: &[u8; 24] = b"noise-libp2p-static-key:";
+/// The length of the authentication tag every noise cipher appends to a transport message.
+const TAGLEN: usize = 16;
 
 
+#[derive(Error, Debug)]
+pub enum NoiseError {
+    #[error("the remote peer authenticated with a different identity key than the one expected")]
+    PeerIdMismatch(),
+    #[error("the handshake payload is not a valid NoiseHandshakePayload")]
+    MalformedPayload(),
+    #[error("the handshake payload does not contain an identity key")]
+    MissingIdentityKey(),
+    #[error("identity keys of type {0} are not supported")]
+    UnsupportedKeyType(i32),
+    #[error("the identity key is not a valid key of its type")]
+    InvalidIdentityKey(),
+    #[error("the identity signature over the noise static key is missing or invalid")]
+    InvalidSignature(),
+    #[error("noise frames are limited to {} bytes, a {0} byte message does not fit", MAX_MESSAGE_LEN)]
+    FrameTooLong(usize),
+    #[error("the remote peer uses a different rekey policy")]
+    RekeyPolicyMismatch(),
+    #[error("the secure channel does not support rekeying")]
+    RekeyUnsupported(),
+}
+
+impl From<IdentityError> for NoiseError {
+    fn from(error: IdentityError) -> Self {
+        match error {
+            IdentityError::UnsupportedKeyType(key_type) => NoiseError::UnsupportedKeyType(key_type),
+            IdentityError::InvalidPublicKey() => NoiseError::InvalidIdentityKey(),
+            IdentityError::InvalidSignature() => NoiseError::InvalidSignature(),
+        }
+    }
+}
+
+type ChannelReader<'a, C> = Box<dyn Fn(&mut C) -> Result<Vec<u8>, Box<dyn Error>> + 'a>;
+type ChannelWriter<'a, C> = Box<dyn Fn(&mut C, &[u8]) -> Result<(), Box<dyn Error>> + 'a>;
+
 pub struct NoiseProtocol {}
 
+/// What the remote peer sent along with its authenticated identity.
+struct RemotePayload {
+    public_key: handshake::PublicKey,
+    muxers: Vec<String>,
+}
+
+/// The authenticated remote peer and the handshake it completed, as reported by [SecureChannel].
+struct Session {
+    remote_peer: PeerId,
+    remote_public_key: handshake::PublicKey,
+    remote_static: Vec<u8>,
+    handshake_hash: Vec<u8>,
+}
+
+/// Controls when a noise channel calls `Rekey()` on its transport ciphers, by default it never does.
+///
+/// Libp2p peers do not support `Rekey`, a policy should only be set when both peers configure the same one.
+/// Each direction of the channel counts the messages and plaintext bytes that pass through it, the writer rekeys
+/// right after sending the message that reaches a limit and the reader right after decrypting that same message.
+/// Nothing on the wire marks a rekey, so both peers must count with the same limits: `with_rekey_policy` exchanges
+/// the policies before applying them and fails with [NoiseError::RekeyPolicyMismatch] when they differ.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RekeyPolicy {
+    /// Rekey after this many messages in one direction.
+    pub messages: Option<u64>,
+    /// Rekey after this many plaintext bytes in one direction.
+    pub bytes: Option<u64>,
+}
+
+impl RekeyPolicy {
+    fn is_due(&self, messages: u64, bytes: u64) -> bool {
+        self.messages.is_some_and(|limit| messages >= limit) || self.bytes.is_some_and(|limit| bytes >= limit)
+    }
+
+    /// The policy as exchanged by `with_rekey_policy`, each limit is a presence byte followed by 8 big-endian bytes.
+    fn encode(&self) -> Vec<u8> {
+        [self.messages, self.bytes]
+            .iter()
+            .flat_map(|limit| [&[limit.is_some() as u8][..], &limit.unwrap_or(0).to_be_bytes()].concat())
+            .collect()
+    }
+}
+
+/// A transport [CipherState] that rekeys itself according to a [RekeyPolicy].
+struct TransportCipher {
+    cipher: CipherState,
+    policy: RekeyPolicy,
+    messages: u64,
+    bytes: u64,
+}
+
+impl TransportCipher {
+    fn new(cipher: CipherState) -> Self {
+        TransportCipher {
+            cipher,
+            policy: RekeyPolicy::default(),
+            messages: 0,
+            bytes: 0,
+        }
+    }
+
+    /// Encrypts a transport message, plaintexts whose ciphertext would not fit in a frame are rejected
+    /// before they use up a nonce.
+    fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        if plaintext.len() + TAGLEN > MAX_MESSAGE_LEN {
+            return Err(NoiseError::FrameTooLong(plaintext.len() + TAGLEN).into());
+        }
+        let ciphertext = self.cipher.encrypt_with_ad(&[], plaintext)?;
+        self.record(plaintext.len())?;
+        Ok(ciphertext)
+    }
+
+    fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let plaintext = self.cipher.decrypt_with_ad(&[], ciphertext)?;
+        self.record(plaintext.len())?;
+        Ok(plaintext)
+    }
+
+    /// Starts counting towards `policy` from zero, so messages sent before it was agreed do not count.
+    fn apply(&mut self, policy: RekeyPolicy) {
+        self.policy = policy;
+        self.messages = 0;
+        self.bytes = 0;
+    }
+
+    /// Counts a message of `len` plaintext bytes and rekeys once the policy is due.
+    fn record(&mut self, len: usize) -> Result<(), Box<dyn Error>> {
+        self.messages += 1;
+        self.bytes += len as u64;
+        if self.policy.is_due(self.messages, self.bytes) {
+            self.cipher.rekey()?;
+            self.messages = 0;
+            self.bytes = 0;
+        }
+        Ok(())
+    }
+}
+
 pub struct NoiseChannel<'a, C: Connection> {
-    encrypter: CipherState,
-    decrypter: CipherState,
-    reader: Box<dyn Fn(&mut C) -> Result<Vec<u8>, Box<dyn Error>> + 'a>,
-    writer: Box<dyn Fn(&mut C, &[u8]) -> Result<(), Box<dyn Error>> + 'a>,
+    encrypter: TransportCipher,
+    decrypter: TransportCipher,
+    reader: ChannelReader<'a, C>,
+    writer: ChannelWriter<'a, C>,
     connection: C,
+    muxer: Option<MuxerProtocol>,
+    session: Session,
+}
+
+/// The async counterpart of [NoiseChannel], noise messages are framed directly on the connection.
+pub struct AsyncNoiseChannel<C: AsyncConnection> {
+    encrypter: TransportCipher,
+    decrypter: TransportCipher,
+    connection: C,
+    muxer: Option<MuxerProtocol>,
+    session: Session,
 }
 
 impl<'a, C> HandShake<'a, C> for NoiseProtocol
@@ -36,6 +187,9 @@ where
     fn upgrade<Reader, Writer>(
         connection: C,
         peer_id: Keypair,
+        remote_peer: Option<PeerId>,
+        initiator: bool,
+        muxers: &[MuxerProtocol],
         reader: Reader,
         writer: Writer,
     ) -> Result<Self::Channel<'a>, Box<dyn Error>>
@@ -46,91 +200,609 @@ where
     {
         let mut connection = connection;
         let static_local = StaticKeypair::new();
-        let mut hss = HandshakeState::new(true, &[], static_local, None, None, None);
-        let init = hss.write_message(&[], vec![MessagePattern::E])?;
-        writer(&mut connection, &init)?;
-
-        // Stage 2: <- e, ee, s, es
-        let encrypted_response = reader(&mut connection)?;
-        let decrypted_response = hss.read_message(
-            &encrypted_response,
-            vec![
-                MessagePattern::E,
-                MessagePattern::Ee,
-                MessagePattern::S,
-                MessagePattern::Es,
-            ],
-        )?;
-        let result: handshake::NoiseHandshakePayload =
-            handshake::NoiseHandshakePayload::decode(&decrypted_response[..]).unwrap();
-
-        // Verify sig
-        // Get remote PeerID
-        let key_proto = handshake::PublicKey::decode(&result.identity_key.unwrap()[..]).unwrap();
-        let remote_id = PublicKey::from_bytes(&key_proto.data).unwrap();
-
-        // Get remote static noise key
-        let remote_static = hss.rs.as_ref().unwrap().as_bytes();
-        let message = [&SIGNATURE_PREFIX[..], &remote_static[..]].concat();
-
-        // Get signature:
-        let signature = Signature::from_bytes(&result.identity_sig.unwrap()).unwrap();
-        // message is remote static key
-        remote_id.verify(&message, &signature)?;
-
-        // Stage 3
-        let auth_payload = Self::auth_payload(peer_id, hss.s.clone())?;
-        let encrypted_payload =
-            hss.write_message(&auth_payload, vec![MessagePattern::S, MessagePattern::Se])?;
-        writer(&mut connection, &encrypted_payload)?;
+        let mut hss =
+            HandshakeState::new(HandshakePattern::xx(), initiator, &[], static_local, None, None, None)?;
+        let mut stage = 0;
+        let mut remote = None;
+        while !hss.is_finished() {
+            if hss.is_my_turn() {
+                let message = Self::write_stage(&mut hss, &peer_id, muxers, stage)?;
+                writer(&mut connection, &message)?;
+            } else {
+                let message = reader(&mut connection)?;
+                remote = Self::read_stage(&mut hss, stage, &message, remote_peer.as_ref())?.or(remote);
+            }
+            stage += 1;
+        }
 
-        let (encrypter, decrypter) = hss.finalize();
+        let remote = remote.expect("the XX pattern always sends the remote identity");
+        let (encrypter, decrypter) = Self::split(&hss, initiator)?;
         Ok(NoiseChannel {
-            encrypter,
-            decrypter,
+            encrypter: TransportCipher::new(encrypter),
+            decrypter: TransportCipher::new(decrypter),
             connection,
             reader: Box::new(reader),
             writer: Box::new(writer),
+            muxer: Self::select_muxer(muxers, &remote.muxers, initiator),
+            session: Self::session(&hss, remote.public_key),
         })
     }
 }
 
+impl<'a, C: Connection> NoiseChannel<'a, C> {
+    /// Rekey both directions of the channel according to `policy`, see [RekeyPolicy].
+    ///
+    /// Both peers must call this before any other transport message, the policies are sent to each other and
+    /// [NoiseError::RekeyPolicyMismatch] is returned unless they are equal. A channel returned as a
+    /// `Box<dyn SecureChannel>` is configured through [SecureChannel::set_rekey_policy] instead.
+    pub fn with_rekey_policy(mut self, policy: RekeyPolicy) -> Result<Self, Box<dyn Error>> {
+        self.set_rekey_policy(policy)?;
+        Ok(self)
+    }
+}
+
 impl<'a, C: Connection> SecureChannel for NoiseChannel<'a, C> {
     fn read(&mut self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
         // Get noise message
         let encrypted_data = (self.reader)(&mut self.connection)?;
         // Decrypt noise message
-        self.decrypter.decrypt_with_ad(&[], &encrypted_data)
+        self.decrypter.decrypt(&encrypted_data)
     }
 
     fn write(&mut self, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
-        let encrypted_data = self.encrypter.encrypt_with_ad(&[], data)?;
+        let encrypted_data = self.encrypter.encrypt(data)?;
         (self.writer)(&mut self.connection, &encrypted_data)?;
         Ok(())
     }
+
+    fn muxer(&self) -> Option<MuxerProtocol> {
+        self.muxer
+    }
+
+    fn remote_peer(&self) -> Option<&PeerId> {
+        Some(&self.session.remote_peer)
+    }
+
+    fn remote_public_key(&self) -> Option<&handshake::PublicKey> {
+        Some(&self.session.remote_public_key)
+    }
+
+    fn remote_static(&self) -> Option<&[u8]> {
+        Some(&self.session.remote_static)
+    }
+
+    fn handshake_hash(&self) -> Option<&[u8]> {
+        Some(&self.session.handshake_hash)
+    }
+
+    fn set_rekey_policy(&mut self, policy: RekeyPolicy) -> Result<(), Box<dyn Error>> {
+        self.write(&policy.encode())?;
+        if self.read()? != policy.encode() {
+            return Err(NoiseError::RekeyPolicyMismatch().into());
+        }
+        self.encrypter.apply(policy);
+        self.decrypter.apply(policy);
+        Ok(())
+    }
+}
+
+impl<C> AsyncHandShake<C> for NoiseProtocol
+where
+    C: AsyncConnection,
+{
+    type Channel = AsyncNoiseChannel<C>;
+
+    async fn upgrade(
+        connection: C,
+        peer_id: Keypair,
+        remote_peer: Option<PeerId>,
+        initiator: bool,
+        muxers: &[MuxerProtocol],
+    ) -> Result<Self::Channel, Box<dyn Error>> {
+        let mut connection = connection;
+        let static_local = StaticKeypair::new();
+        let mut hss =
+            HandshakeState::new(HandshakePattern::xx(), initiator, &[], static_local, None, None, None)?;
+        let mut stage = 0;
+        let mut remote = None;
+        while !hss.is_finished() {
+            if hss.is_my_turn() {
+                let message = Self::write_stage(&mut hss, &peer_id, muxers, stage)?;
+                write_frame(&mut connection, &message).await?;
+            } else {
+                let message = read_frame(&mut connection).await?;
+                remote = Self::read_stage(&mut hss, stage, &message, remote_peer.as_ref())?.or(remote);
+            }
+            stage += 1;
+        }
+
+        let remote = remote.expect("the XX pattern always sends the remote identity");
+        let (encrypter, decrypter) = Self::split(&hss, initiator)?;
+        Ok(AsyncNoiseChannel {
+            encrypter: TransportCipher::new(encrypter),
+            decrypter: TransportCipher::new(decrypter),
+            connection,
+            muxer: Self::select_muxer(muxers, &remote.muxers, initiator),
+            session: Self::session(&hss, remote.public_key),
+        })
+    }
+}
+
+impl<C: AsyncConnection> AsyncNoiseChannel<C> {
+    /// Rekey both directions of the channel according to `policy`, see [NoiseChannel::with_rekey_policy].
+    pub async fn with_rekey_policy(mut self, policy: RekeyPolicy) -> Result<Self, Box<dyn Error>> {
+        self.write(&policy.encode()).await?;
+        if self.read().await? != policy.encode() {
+            return Err(NoiseError::RekeyPolicyMismatch().into());
+        }
+        self.encrypter.apply(policy);
+        self.decrypter.apply(policy);
+        Ok(self)
+    }
+}
+
+impl<C: AsyncConnection> AsyncSecureChannel for AsyncNoiseChannel<C> {
+    async fn read(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let encrypted_data = read_frame(&mut self.connection).await?;
+        self.decrypter.decrypt(&encrypted_data)
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        let encrypted_data = self.encrypter.encrypt(data)?;
+        write_frame(&mut self.connection, &encrypted_data).await
+    }
+
+    fn muxer(&self) -> Option<MuxerProtocol> {
+        self.muxer
+    }
+
+    fn remote_peer(&self) -> Option<&PeerId> {
+        Some(&self.session.remote_peer)
+    }
+
+    fn remote_public_key(&self) -> Option<&handshake::PublicKey> {
+        Some(&self.session.remote_public_key)
+    }
+
+    fn remote_static(&self) -> Option<&[u8]> {
+        Some(&self.session.remote_static)
+    }
+
+    fn handshake_hash(&self) -> Option<&[u8]> {
+        Some(&self.session.handshake_hash)
+    }
+}
+
+/// Reads a single noise message prefixed by its 2 byte big-endian length.
+async fn read_frame<C: AsyncRead + Unpin>(connection: &mut C) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut msg_len = [0u8; 2];
+    connection.read_exact(&mut msg_len).await?;
+    let mut encrypted = vec![0u8; u16::from_be_bytes(msg_len).into()];
+    connection.read_exact(&mut encrypted).await?;
+    Ok(encrypted)
+}
+
+/// Writes a single noise message prefixed by its 2 byte big-endian length.
+async fn write_frame<C: AsyncWrite + Unpin>(connection: &mut C, message: &[u8]) -> Result<(), Box<dyn Error>> {
+    let data_len = u16::try_from(message.len()).map_err(|_| NoiseError::FrameTooLong(message.len()))?;
+    connection.write_all(&[&data_len.to_be_bytes()[..], message].concat()).await?;
+    connection.flush().await?;
+    Ok(())
 }
 
 impl NoiseProtocol {
+    /// Produces the handshake message for `stage`, every stage which sends a static key carries our signed identity
+    /// along with the `muxers` we support.
+    fn write_stage(
+        hss: &mut HandshakeState,
+        keypair: &Keypair,
+        muxers: &[MuxerProtocol],
+        stage: usize,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let payload = if stage == 0 { vec![] } else { Self::auth_payload(keypair, &hss.s, muxers)? };
+        hss.write_message(&payload)
+    }
+
+    /// Consumes the handshake message for `stage`, verifying the remote identity once its static key is known
+    /// and checking it against `remote_peer` when one is expected.
+    /// Returns what the remote sent with its identity, [None] for stages before it is sent.
+    fn read_stage(
+        hss: &mut HandshakeState,
+        stage: usize,
+        message: &[u8],
+        remote_peer: Option<&PeerId>,
+    ) -> Result<Option<RemotePayload>, Box<dyn Error>> {
+        let payload = hss.read_message(message)?;
+        if stage == 0 {
+            return Ok(None);
+        }
+        let payload = handshake::NoiseHandshakePayload::decode(&payload[..]).map_err(|_| NoiseError::MalformedPayload())?;
+        let public_key = Self::verify_payload(&payload, hss)?;
+        if remote_peer.is_some_and(|expected| *expected != PeerId::from_public_key(&public_key)) {
+            return Err(NoiseError::PeerIdMismatch().into());
+        }
+        let muxers = payload.extensions.map(|extensions| extensions.stream_muxers).unwrap_or_default();
+        Ok(Some(RemotePayload { public_key, muxers }))
+    }
+
+    /// Records who the remote authenticated as and the final state of the finished handshake.
+    fn session(hss: &HandshakeState, remote_public_key: handshake::PublicKey) -> Session {
+        let remote_static = hss.rs.as_ref().expect("the XX pattern always sends the remote static key");
+        Session {
+            remote_peer: PeerId::from_public_key(&remote_public_key),
+            remote_public_key,
+            remote_static: remote_static.as_bytes().to_vec(),
+            handshake_hash: hss.handshake_hash().to_vec(),
+        }
+    }
+
+    /// Picks the first muxer in the initiator's list that the responder supports, both peers arrive at the same
+    /// choice since the initiator's list is the one that orders it.
+    ///
+    /// See [early muxer negotiation](https://github.com/libp2p/specs/blob/master/noise/README.md#libp2p-data-in-handshake-messages)
+    fn select_muxer(local: &[MuxerProtocol], remote: &[String], initiator: bool) -> Option<MuxerProtocol> {
+        if initiator {
+            local.iter().find(|muxer| remote.iter().any(|protocol| protocol == muxer.protocol())).copied()
+        } else {
+            remote.iter().find_map(|protocol| local.iter().find(|muxer| muxer.protocol() == protocol)).copied()
+        }
+    }
+
+    /// Splits the finished handshake into an `(encrypter, decrypter)` pair,
+    /// the initiator encrypts with the first cipher and the responder with the second.
+    fn split(hss: &HandshakeState, initiator: bool) -> Result<(CipherState, CipherState), HandshakeError> {
+        let (c1, c2) = hss.finalize()?;
+        Ok(if initiator { (c1, c2) } else { (c2, c1) })
+    }
+
     fn auth_payload(
-        keypair: Keypair,
-        noise_static_key: StaticKeypair,
+        keypair: &Keypair,
+        noise_static_key: &StaticKeypair,
+        muxers: &[MuxerProtocol],
     ) -> Result<Vec<u8>, Box<dyn Error>> {
         // Create payloads to be serialized
         let mut payload = handshake::NoiseHandshakePayload::default();
 
         // Add the PeerID to the HandshakePayload
-        let key_payload = handshake::PublicKey { r#type: 1, data: keypair.public.as_bytes().to_vec() };
+        let key_payload = keypair.public().to_protobuf();
         let mut buf = vec![];
         key_payload.encode(&mut buf)?;
         payload.identity_key = Some(buf);
 
         // Add local signature to payload
         let data = [&SIGNATURE_PREFIX[..], &noise_static_key.0.as_bytes()[..]].concat();
-        let signature = keypair.sign(&data).to_bytes().to_vec();
-        payload.identity_sig = Some(signature);
+        payload.identity_sig = Some(keypair.sign(&data));
+
+        // Advertise our stream muxers so one can be agreed without another round trip
+        if !muxers.is_empty() {
+            payload.extensions = Some(handshake::NoiseExtensions {
+                webtransport_certhashes: vec![],
+                stream_muxers: muxers.iter().map(|muxer| muxer.protocol().to_owned()).collect(),
+            });
+        }
 
         let mut buf = vec![];
         payload.encode(&mut buf)?;
         Ok(buf)
     }
+
+    /// Verifies that the remote identity key in a decrypted [handshake::NoiseHandshakePayload]
+    /// signed the remote static noise key learned during the handshake.
+    fn verify_payload(
+        payload: &handshake::NoiseHandshakePayload,
+        hss: &HandshakeState,
+    ) -> Result<handshake::PublicKey, NoiseError> {
+        // Get remote PeerID
+        let identity_key = payload.identity_key.as_ref().ok_or(NoiseError::MissingIdentityKey())?;
+        let key_proto = handshake::PublicKey::decode(&identity_key[..]).map_err(|_| NoiseError::MalformedPayload())?;
+        let remote_id = PublicKey::from_protobuf(&key_proto)?;
+
+        // Get remote static noise key, the pattern always sends it before the payload that signs it
+        let remote_static = hss.rs.as_ref().expect("the remote static key is read before its signature");
+        let message = [&SIGNATURE_PREFIX[..], &remote_static.as_bytes()[..]].concat();
+
+        // message is remote static key
+        let signature = payload.identity_sig.as_ref().ok_or(NoiseError::InvalidSignature())?;
+        remote_id.verify(&message, signature)?;
+        Ok(key_proto)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use prost::Message;
+
+    use super::{NoiseError, NoiseProtocol, RekeyPolicy, TransportCipher};
+    use crate::auth::noise::{
+        cipher::CipherState,
+        handshake_state::{HandshakeState, StaticKeypair},
+        pattern::HandshakePattern,
+    };
+    use crate::auth::{AsyncSecureChannel, AuthProtocol};
+    use crate::connection::{
+        async_multistream::AsyncMultistream, multistream::Multistream, AsyncConnection, AsyncIncoming, AsyncListener,
+        Connection,
+    };
+    use crate::handshake;
+    use crate::identity::Keypair;
+    use crate::muxer::{Muxer, MuxerProtocol};
+    use crate::peer_id::PeerId;
+
+    #[test]
+    fn test_loopback_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let responder = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let connection = Multistream::new(stream, false);
+            // Peers authenticate with different identity key types
+            let keypair = Keypair::generate_secp256k1();
+            let mut channel = Multistream::upgrade::<NoiseProtocol>(connection, keypair, None, &[]).unwrap();
+            let received = channel.read().unwrap();
+            channel.write(&[&received[..], b" pong"].concat()).unwrap();
+            let handshake_hash = channel.handshake_hash().unwrap().to_vec();
+            channel.write(&handshake_hash).unwrap();
+        });
+
+        let connection = Multistream::new(TcpStream::connect(addr).unwrap(), true);
+        let keypair = Keypair::generate_ed25519();
+        let mut channel = Multistream::upgrade::<NoiseProtocol>(connection, keypair, None, &[]).unwrap();
+        channel.write(b"ping").unwrap();
+        assert_eq!(channel.read().unwrap(), b"ping pong");
+
+        // Both peers finish with the same handshake hash, which binds the session to this channel
+        assert_eq!(channel.read().unwrap(), channel.handshake_hash().unwrap());
+        let remote_key = channel.remote_public_key().unwrap();
+        assert_eq!(remote_key.r#type, handshake::KeyType::Secp256k1 as i32);
+        assert_eq!(channel.remote_peer(), Some(&PeerId::from_public_key(remote_key)));
+        assert_eq!(channel.remote_static().unwrap().len(), 32);
+        responder.join().unwrap();
+    }
+
+    /// Runs the first two XX messages against a responder that sends `payload` with its static key,
+    /// returning the error the initiator reports for it.
+    fn read_responder_payload(payload: &handshake::NoiseHandshakePayload) -> NoiseError {
+        let mut initiator: HandshakeState =
+            HandshakeState::new(HandshakePattern::xx(), true, &[], StaticKeypair::new(), None, None, None).unwrap();
+        let mut responder: HandshakeState =
+            HandshakeState::new(HandshakePattern::xx(), false, &[], StaticKeypair::new(), None, None, None).unwrap();
+        responder.read_message(&initiator.write_message(&[]).unwrap()).unwrap();
+        let message = responder.write_message(&payload.encode_to_vec()).unwrap();
+        let error = NoiseProtocol::read_stage(&mut initiator, 1, &message, None).err().unwrap();
+        *error.downcast::<NoiseError>().unwrap()
+    }
+
+    #[test]
+    fn test_malformed_payloads() {
+        let keypair = Keypair::generate_ed25519();
+        let identity_key = Some(keypair.public().to_protobuf().encode_to_vec());
+        let payload = |identity_key: Option<Vec<u8>>, identity_sig: Option<Vec<u8>>| handshake::NoiseHandshakePayload {
+            identity_key,
+            identity_sig,
+            extensions: None,
+        };
+        let key = |r#type: i32, data: Vec<u8>| Some(handshake::PublicKey { r#type, data }.encode_to_vec());
+
+        assert!(matches!(read_responder_payload(&payload(None, None)), NoiseError::MissingIdentityKey()));
+        assert!(matches!(
+            read_responder_payload(&payload(Some(vec![0xff, 0xff]), None)),
+            NoiseError::MalformedPayload()
+        ));
+        assert!(matches!(
+            read_responder_payload(&payload(key(7, vec![0; 32]), None)),
+            NoiseError::UnsupportedKeyType(7)
+        ));
+        assert!(matches!(
+            read_responder_payload(&payload(key(handshake::KeyType::Ed25519 as i32, vec![0; 3]), None)),
+            NoiseError::InvalidIdentityKey()
+        ));
+        assert!(matches!(read_responder_payload(&payload(identity_key.clone(), None)), NoiseError::InvalidSignature()));
+        // Signed by the right key, but not over the responder's static key
+        let signature = Some(keypair.sign(b"noise-libp2p-static-key:"));
+        assert!(matches!(read_responder_payload(&payload(identity_key, signature)), NoiseError::InvalidSignature()));
+    }
+
+    #[test]
+    fn test_expected_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let responder_keypair = Keypair::generate_ed25519();
+        let responder_id = responder_keypair.public().to_peer_id();
+
+        let responder = thread::spawn(move || {
+            // The first dialer gives up once it sees the wrong identity
+            let (stream, _) = listener.accept().unwrap();
+            let connection = Multistream::new(stream, false);
+            assert!(Multistream::upgrade::<NoiseProtocol>(connection, responder_keypair.clone(), None, &[]).is_err());
+
+            let (stream, _) = listener.accept().unwrap();
+            let connection = Multistream::new(stream, false);
+            let mut channel = Multistream::upgrade::<NoiseProtocol>(connection, responder_keypair, None, &[]).unwrap();
+            channel.write(b"authenticated").unwrap();
+        });
+
+        let connection = Multistream::new(TcpStream::connect(addr).unwrap(), true);
+        let impostor = Keypair::generate_ed25519().public().to_peer_id();
+        let error = Multistream::upgrade::<NoiseProtocol>(connection, Keypair::generate_ed25519(), Some(impostor), &[])
+            .err()
+            .unwrap();
+        assert!(matches!(error.downcast_ref(), Some(NoiseError::PeerIdMismatch())));
+
+        let connection = Multistream::new(TcpStream::connect(addr).unwrap(), true);
+        let keypair = Keypair::generate_ed25519();
+        let mut channel = Multistream::upgrade::<NoiseProtocol>(connection, keypair, Some(responder_id), &[]).unwrap();
+        assert_eq!(channel.read().unwrap(), b"authenticated");
+        responder.join().unwrap();
+    }
+
+    #[test]
+    fn test_muxer_negotiation() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let responder = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let connection = Multistream::new(stream, false);
+            let keypair = Keypair::generate_ed25519();
+            let muxers = [MuxerProtocol::Mplex, MuxerProtocol::Yamux];
+            let channel = Multistream::upgrade::<NoiseProtocol>(connection, keypair, None, &muxers).unwrap();
+            assert_eq!(channel.muxer(), Some(MuxerProtocol::Yamux));
+            let muxer = Muxer::negotiate(channel, &muxers, false).unwrap();
+            let mut stream = muxer.accept_stream().unwrap();
+            let received = stream.read().unwrap();
+            stream.write(&received).unwrap();
+        });
+
+        // The initiator's preference wins, the muxer starts without multistream-select
+        let connection = Multistream::new(TcpStream::connect(addr).unwrap(), true);
+        let keypair = Keypair::generate_ed25519();
+        let muxers = [MuxerProtocol::Yamux, MuxerProtocol::Mplex];
+        let channel = Multistream::upgrade::<NoiseProtocol>(connection, keypair, None, &muxers).unwrap();
+        assert_eq!(channel.muxer(), Some(MuxerProtocol::Yamux));
+        let muxer = Muxer::negotiate(channel, &muxers, true).unwrap();
+        let mut stream = muxer.open_stream().unwrap();
+        stream.write(b"echo").unwrap();
+        assert_eq!(stream.read().unwrap(), b"echo");
+        responder.join().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rekey_policy() {
+        let listener = AsyncMultistream::listen("127.0.0.1:0".parse().unwrap(), AuthProtocol::Noise)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let policy = RekeyPolicy {
+            messages: Some(2),
+            bytes: Some(10),
+        };
+
+        let dialer_keypair = Keypair::generate_ed25519();
+        let dialer_id = dialer_keypair.public().to_peer_id();
+        let dialer = tokio::spawn(async move {
+            let connection = AsyncMultistream::connect(addr, AuthProtocol::Noise).await.unwrap();
+            let keypair = dialer_keypair;
+            let mut channel = AsyncMultistream::upgrade::<NoiseProtocol>(connection, keypair, None, &[])
+                .await
+                .unwrap()
+                .with_rekey_policy(policy)
+                .await
+                .unwrap();
+            for message in [&b"a"[..], b"bb", b"cccccccccccc", b"d", b"e"] {
+                channel.write(message).await.unwrap();
+                assert_eq!(channel.read().await.unwrap(), message);
+            }
+        });
+
+        let connection = listener.accept().await.unwrap().negotiate().await.unwrap();
+        let keypair = Keypair::generate_ed25519();
+        let mut channel = AsyncMultistream::upgrade::<NoiseProtocol>(connection, keypair, None, &[])
+            .await
+            .unwrap()
+            .with_rekey_policy(policy)
+            .await
+            .unwrap();
+        assert_eq!(channel.remote_peer(), Some(&dialer_id));
+        for _ in 0..5 {
+            let message = channel.read().await.unwrap();
+            channel.write(&message).await.unwrap();
+        }
+        dialer.await.unwrap();
+    }
+
+    #[test]
+    fn test_sync_rekey_policy() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let policy = RekeyPolicy {
+            messages: Some(2),
+            bytes: Some(10),
+        };
+
+        let responder = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let connection = Multistream::new(stream, false);
+            let mut channel = Multistream::upgrade::<NoiseProtocol>(connection, Keypair::generate_ed25519(), None, &[])
+                .unwrap();
+            channel.set_rekey_policy(policy).unwrap();
+            for _ in 0..5 {
+                let message = channel.read().unwrap();
+                channel.write(&message).unwrap();
+            }
+        });
+
+        let connection = Multistream::new(TcpStream::connect(addr).unwrap(), true);
+        let mut channel = Multistream::upgrade::<NoiseProtocol>(connection, Keypair::generate_ed25519(), None, &[])
+            .unwrap();
+        channel.set_rekey_policy(policy).unwrap();
+        for message in [&b"a"[..], b"bb", b"cccccccccccc", b"d", b"e"] {
+            channel.write(message).unwrap();
+            assert_eq!(channel.read().unwrap(), message);
+        }
+        responder.join().unwrap();
+    }
+
+    #[test]
+    fn test_rekey_thresholds() {
+        let transport = |policy| {
+            let mut cipher = CipherState::new();
+            cipher.initialise_key(&[5u8; 32]);
+            TransportCipher { policy, ..TransportCipher::new(cipher) }
+        };
+        let policy = RekeyPolicy { messages: Some(2), bytes: None };
+        let mut encrypter = transport(policy);
+        let mut decrypter = transport(policy);
+        let mut without_policy = transport(RekeyPolicy::default());
+        let mut later_policy = transport(RekeyPolicy { messages: Some(3), bytes: None });
+
+        for message in [b"one", b"two"] {
+            let ciphertext = encrypter.encrypt(message).unwrap();
+            for receiver in [&mut decrypter, &mut without_policy, &mut later_policy] {
+                assert_eq!(receiver.decrypt(&ciphertext).unwrap(), message);
+            }
+        }
+        // Past the threshold only a peer counting with the same policy has the new key
+        let ciphertext = encrypter.encrypt(b"three").unwrap();
+        assert_eq!(decrypter.decrypt(&ciphertext).unwrap(), b"three");
+        assert!(without_policy.decrypt(&ciphertext).is_err());
+        assert!(later_policy.decrypt(&ciphertext).is_err());
+
+        // The message that carried the policy exchange does not count towards it
+        let mut exchanged = transport(RekeyPolicy::default());
+        exchanged.encrypt(b"policy").unwrap();
+        exchanged.apply(RekeyPolicy { messages: None, bytes: Some(10) });
+        assert_eq!((exchanged.messages, exchanged.bytes), (0, 0));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rekey_policy_mismatch() {
+        let listener = AsyncMultistream::listen("127.0.0.1:0".parse().unwrap(), AuthProtocol::Noise)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mismatch = |result: Result<_, Box<dyn std::error::Error>>| {
+            matches!(result.err().unwrap().downcast_ref(), Some(NoiseError::RekeyPolicyMismatch()))
+        };
+
+        let dialer = tokio::spawn(async move {
+            let connection = AsyncMultistream::connect(addr, AuthProtocol::Noise).await.unwrap();
+            let channel = AsyncMultistream::upgrade::<NoiseProtocol>(connection, Keypair::generate_ed25519(), None, &[])
+                .await
+                .unwrap();
+            let policy = RekeyPolicy { messages: Some(2), bytes: None };
+            mismatch(channel.with_rekey_policy(policy).await.map(|_| ()))
+        });
+
+        let connection = listener.accept().await.unwrap().negotiate().await.unwrap();
+        let channel = AsyncMultistream::upgrade::<NoiseProtocol>(connection, Keypair::generate_ed25519(), None, &[])
+            .await
+            .unwrap();
+        let policy = RekeyPolicy { messages: Some(3), bytes: None };
+        assert!(mismatch(channel.with_rekey_policy(policy).await.map(|_| ())));
+        assert!(dialer.await.unwrap());
+    }
 }
@@ -1,40 +1,42 @@
-use super::{cipher, HASHLEN};
-use sha2::{Digest, Sha256};
+use super::cipher;
+use super::crypto::{Cipher, Hash};
 use std::error::Error;
-pub struct SymmetricState {
+use std::marker::PhantomData;
+
+/// Cipher keys are always 32 bytes, `HKDF` outputs are truncated to this length when `HASHLEN` is 64.
+const KEYLEN: usize = 32;
+
+pub struct SymmetricState<C: Cipher, H: Hash> {
     ck: Vec<u8>,
     h: Vec<u8>,
-    pub cipher_state: cipher::CipherState,
+    pub cipher_state: cipher::CipherState<C>,
+    hash: PhantomData<H>,
 }
 
-impl SymmetricState {
+impl<C: Cipher, H: Hash> SymmetricState<C, H> {
     /// Calls "Initialize" on the SymmetricState object defined in the protocol:
     /// [SymmetricState](https://noiseprotocol.org/noise.html#the-symmetricstate-object)
-    pub fn new(protocol_name: &[u8]) -> SymmetricState {
-        let h: Vec<u8> = if protocol_name.len() <= HASHLEN {
-            let mut h_buf = [0u8; HASHLEN];
+    pub fn new(protocol_name: &[u8]) -> SymmetricState<C, H> {
+        let h: Vec<u8> = if protocol_name.len() <= H::HASHLEN {
+            let mut h_buf = vec![0u8; H::HASHLEN];
             h_buf[..protocol_name.len()].copy_from_slice(protocol_name);
-            h_buf.to_vec()
+            h_buf
         } else {
-            let mut hasher = Sha256::new();
-            hasher.update(protocol_name);
-            hasher.finalize_reset()[..].to_vec()
+            H::hash(&[protocol_name])
         };
         let ck = h.clone();
         SymmetricState {
             ck,
             h,
             cipher_state: cipher::CipherState::new(),
+            hash: PhantomData,
         }
     }
 
     /// Calls "MixHash" on the SymmetricState object defined in the protocol:
     /// [SymmetricState](https://noiseprotocol.org/noise.html#the-symmetricstate-object)
     pub fn mix_hash(&mut self, data: &[u8]) {
-        let mut hasher = Sha256::new();
-        hasher.update(&self.h);
-        hasher.update(data);
-        self.h = hasher.finalize_reset()[..].to_vec();
+        self.h = H::hash(&[&self.h, data]);
     }
 
     /// Calls "MixKey" on the SymmetricState object defined in the protocol:
@@ -42,21 +44,20 @@ impl SymmetricState {
     ///
     /// This function produces a new cipher key to feed into [cipher::CipherState] which it will use to decrypt and encrypt payloads.
     pub fn mix_key(&mut self, input_key_material: &[u8]) {
-        let (ck, temp_k, _) = cipher::hkdf(&self.ck, input_key_material, 2);
+        let (ck, temp_k, _) = cipher::hkdf::<H>(&self.ck, input_key_material, 2);
         self.ck = ck;
-        self.cipher_state.initialise_key(&temp_k);
+        self.cipher_state.initialise_key(&temp_k[..KEYLEN]);
     }
 
-    /// Calls "MixKey" on the SymmetricState object defined in the protocol:
+    /// Calls "MixKeyAndHash" on the SymmetricState object defined in the protocol:
     /// [SymmetricState](https://noiseprotocol.org/noise.html#the-symmetricstate-object)
-    /// 
-    /// NOTE: At the moment no protocols that support this have been implemented. Provided for completeness.
-    #[allow(dead_code)]
-    fn mix_key_and_hash(&mut self, input_key_material: &[u8]) {
-        let (ck, temp_h, temp_k) = cipher::hkdf(&self.ck, input_key_material, 3);
+    ///
+    /// Used to mix pre-shared keys into the handshake for the `psk` tokens.
+    pub fn mix_key_and_hash(&mut self, input_key_material: &[u8]) {
+        let (ck, temp_h, temp_k) = cipher::hkdf::<H>(&self.ck, input_key_material, 3);
         self.ck = ck;
         self.mix_hash(&temp_h);
-        self.cipher_state.initialise_key(&temp_k.unwrap());
+        self.cipher_state.initialise_key(&temp_k.unwrap()[..KEYLEN]);
     }
 
     /// Calls "EncryptAndHash" on the SymmetricState object defined in the protocol:
@@ -75,13 +76,21 @@ impl SymmetricState {
         Ok(plaintext)
     }
 
+    /// Calls "GetHandshakeHash" on the SymmetricState object defined in the protocol:
+    /// [SymmetricState](https://noiseprotocol.org/noise.html#the-symmetricstate-object)
+    ///
+    /// Once the handshake is finished `h` uniquely identifies the session and can be used for channel binding.
+    pub fn handshake_hash(&self) -> &[u8] {
+        &self.h
+    }
+
     /// Calls "Split" on the SymmetricState object defined in the protocol:
     /// [SymmetricState](https://noiseprotocol.org/noise.html#the-symmetricstate-object)
-    pub fn split(&self) -> (cipher::CipherState, cipher::CipherState) {
-        let (temp_k1, temp_k2, _) = cipher::hkdf(&self.ck, &[], 2);
+    pub fn split(&self) -> (cipher::CipherState<C>, cipher::CipherState<C>) {
+        let (temp_k1, temp_k2, _) = cipher::hkdf::<H>(&self.ck, &[], 2);
         let (mut c1, mut c2) = (cipher::CipherState::new(), cipher::CipherState::new());
-        c1.initialise_key(&temp_k1);
-        c2.initialise_key(&temp_k2);
+        c1.initialise_key(&temp_k1[..KEYLEN]);
+        c2.initialise_key(&temp_k2[..KEYLEN]);
         (c1, c2)
     }
 }
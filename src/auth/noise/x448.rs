@@ -0,0 +1,189 @@
+//! The X448 function from [RFC 7748](https://www.rfc-editor.org/rfc/rfc7748), used by the `448` noise DH functions.
+//!
+//! Field arithmetic comes from the formally verified, constant time `p448` code of [fiat_crypto], and the
+//! ladder swaps with masks rather than branches, so no timing depends on the scalar or the u-coordinate.
+use fiat_crypto::p448_solinas_64::{
+    fiat_p448_add, fiat_p448_carry, fiat_p448_carry_mul, fiat_p448_carry_square, fiat_p448_from_bytes,
+    fiat_p448_loose_field_element, fiat_p448_relax, fiat_p448_selectznz, fiat_p448_sub, fiat_p448_tight_field_element,
+    fiat_p448_to_bytes,
+};
+
+pub const KEYLEN: usize = 56;
+
+/// `u = 5`, the u-coordinate of the base point.
+const BASE_POINT: [u8; KEYLEN] = {
+    let mut point = [0u8; KEYLEN];
+    point[0] = 5;
+    point
+};
+
+/// `(156326 - 2) / 4`
+const A24: u64 = 39081;
+
+/// An element of the field modulo `p = 2^448 - 2^224 - 1`.
+#[derive(Clone, Copy)]
+struct Field(fiat_p448_tight_field_element);
+
+impl Field {
+    fn from_bytes(bytes: &[u8; KEYLEN]) -> Self {
+        let mut element = fiat_p448_tight_field_element([0; 8]);
+        fiat_p448_from_bytes(&mut element, bytes);
+        Field(element)
+    }
+
+    fn from_u64(value: u64) -> Self {
+        let mut bytes = [0u8; KEYLEN];
+        bytes[..8].copy_from_slice(&value.to_le_bytes());
+        Self::from_bytes(&bytes)
+    }
+
+    fn to_bytes(self) -> [u8; KEYLEN] {
+        let mut bytes = [0u8; KEYLEN];
+        fiat_p448_to_bytes(&mut bytes, &self.0);
+        bytes
+    }
+
+    fn relax(self) -> fiat_p448_loose_field_element {
+        let mut loose = fiat_p448_loose_field_element([0; 8]);
+        fiat_p448_relax(&mut loose, &self.0);
+        loose
+    }
+
+    fn carry(loose: &fiat_p448_loose_field_element) -> Self {
+        let mut element = fiat_p448_tight_field_element([0; 8]);
+        fiat_p448_carry(&mut element, loose);
+        Field(element)
+    }
+
+    fn add(self, other: Self) -> Self {
+        let mut sum = fiat_p448_loose_field_element([0; 8]);
+        fiat_p448_add(&mut sum, &self.0, &other.0);
+        Self::carry(&sum)
+    }
+
+    fn sub(self, other: Self) -> Self {
+        let mut difference = fiat_p448_loose_field_element([0; 8]);
+        fiat_p448_sub(&mut difference, &self.0, &other.0);
+        Self::carry(&difference)
+    }
+
+    fn mul(self, other: Self) -> Self {
+        let mut element = fiat_p448_tight_field_element([0; 8]);
+        fiat_p448_carry_mul(&mut element, &self.relax(), &other.relax());
+        Field(element)
+    }
+
+    fn square(self) -> Self {
+        let mut element = fiat_p448_tight_field_element([0; 8]);
+        fiat_p448_carry_square(&mut element, &self.relax());
+        Field(element)
+    }
+
+    /// `self^(p - 2)`, the inverse by Fermat's little theorem. The exponent is public, every bit of
+    /// `p - 2 = 2^448 - 2^224 - 3` is set apart from bits 224 and 1.
+    fn invert(self) -> Self {
+        let mut result = Field::from_u64(1);
+        for bit in (0..448).rev() {
+            result = result.square();
+            if bit != 224 && bit != 1 {
+                result = result.mul(self);
+            }
+        }
+        result
+    }
+
+    /// Swaps `a` and `b` when `swap` is 1 without branching on it.
+    fn conditional_swap(swap: u8, a: &mut Self, b: &mut Self) {
+        let (mut new_a, mut new_b) = ([0u64; 8], [0u64; 8]);
+        fiat_p448_selectznz(&mut new_a, swap, &a.0 .0, &b.0 .0);
+        fiat_p448_selectznz(&mut new_b, swap, &b.0 .0, &a.0 .0);
+        (a.0 .0, b.0 .0) = (new_a, new_b);
+    }
+}
+
+/// Computes `X448(k, u)` using the Montgomery ladder.
+///
+/// See [The X25519 and X448 Functions](https://www.rfc-editor.org/rfc/rfc7748#section-5)
+pub fn x448(scalar: &[u8; KEYLEN], u: &[u8; KEYLEN]) -> [u8; KEYLEN] {
+    let mut k = *scalar;
+    k[0] &= 252;
+    k[55] |= 128;
+
+    let a24 = Field::from_u64(A24);
+    let x_1 = Field::from_bytes(u);
+    let (mut x_2, mut z_2) = (Field::from_u64(1), Field::from_u64(0));
+    let (mut x_3, mut z_3) = (x_1, Field::from_u64(1));
+    let mut swap = 0u8;
+    for t in (0..448).rev() {
+        let k_t = (k[t / 8] >> (t % 8)) & 1;
+        swap ^= k_t;
+        Field::conditional_swap(swap, &mut x_2, &mut x_3);
+        Field::conditional_swap(swap, &mut z_2, &mut z_3);
+        swap = k_t;
+
+        let a = x_2.add(z_2);
+        let aa = a.square();
+        let b = x_2.sub(z_2);
+        let bb = b.square();
+        let e = aa.sub(bb);
+        let c = x_3.add(z_3);
+        let d = x_3.sub(z_3);
+        let da = d.mul(a);
+        let cb = c.mul(b);
+        x_3 = da.add(cb).square();
+        z_3 = x_1.mul(da.sub(cb).square());
+        x_2 = aa.mul(bb);
+        z_2 = e.mul(aa.add(a24.mul(e)));
+    }
+    Field::conditional_swap(swap, &mut x_2, &mut x_3);
+    Field::conditional_swap(swap, &mut z_2, &mut z_3);
+    x_2.mul(z_2.invert()).to_bytes()
+}
+
+/// Derives the public key of `scalar` by multiplying the base point.
+pub fn x448_base(scalar: &[u8; KEYLEN]) -> [u8; KEYLEN] {
+    x448(scalar, &BASE_POINT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{x448, x448_base, KEYLEN};
+
+    fn hex(data: &str) -> [u8; KEYLEN] {
+        let bytes: Vec<u8> = (0..data.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&data[index..index + 2], 16).unwrap())
+            .collect();
+        bytes.try_into().unwrap()
+    }
+
+    #[test]
+    fn test_rfc7748_vectors() {
+        // Section 5.2
+        let scalar = hex("3d262fddf9ec8e88495266fea19a34d28882acef045104d0d1aae121700a779c984c24f8cdd78fbff44943eba368f54b29259a4f1c600ad3");
+        let u = hex("06fce640fa3487bfda5f6cf2d5263f8aad88334cbd07437f020f08f9814dc031ddbdc38c19c6da2583fa5429db94ada18aa7a7fb4ef8a086");
+        let expected = hex("ce3e4ff95a60dc6697da1db1d85e6afbdf79b50a2412d7546d5f239fe14fbaadeb445fc66a01b0779d98223961111e21766282f73dd96b6f");
+        assert_eq!(x448(&scalar, &u), expected);
+
+        // Section 6.2
+        let alice = hex("9a8f4925d1519f5775cf46b04b5800d4ee9ee8bae8bc5565d498c28dd9c9baf574a9419744897391006382a6f127ab1d9ac2d8c0a598726b");
+        let bob = hex("1c306a7ac2a0e2e0990b294470cba339e6453772b075811d8fad0d1d6927c120bb5ee8972b0d3e21374c9c921b09d1b0366f10b65173992d");
+        let alice_public = x448_base(&alice);
+        let bob_public = x448_base(&bob);
+        assert_eq!(alice_public, hex("9b08f7cc31b7e3e67d22d5aea121074a273bd2b83de09c63faa73d2c22c5d9bbc836647241d953d40c5b12da88120d53177f80e532c41fa0"));
+        assert_eq!(bob_public, hex("3eb7a829b0cd20f5bcfc0b599b6feccf6da4627107bdb0d4f345b43027d8b972fc3e34fb4232a13ca706dcb57aec3dae07bdc1c67bf33609"));
+        let shared = hex("07fff4181ac6cc95ec1c16a94a0f74d12da232ce40a77552281d282bb60c0b56fd2464c335543936521c24403085d59a449a5037514a879d");
+        assert_eq!(x448(&alice, &bob_public), shared);
+        assert_eq!(x448(&bob, &alice_public), shared);
+
+        // Section 5.2, iterating `k, u = X448(k, u), k` from `k = u = 5`
+        let (mut k, mut u) = (super::BASE_POINT, super::BASE_POINT);
+        for iteration in 1..=1000 {
+            (k, u) = (x448(&k, &u), k);
+            if iteration == 1 {
+                assert_eq!(k, hex("3f482c8a9f19b01e6c46ee9711d9dc14fd4bf67af30765c2ae2b846a4d23a8cd0db897086239492caf350b51f833868b9bc2b3bca9cf4113"));
+            }
+        }
+        assert_eq!(k, hex("aa3b4749d55b9daf1e5b00288826c467274ce3ebbdd5c17b975e09d4af6c67cf10d087202db88286e2b79fceea3ec353ef54faa26e219f38"));
+    }
+}
@@ -0,0 +1,455 @@
+use std::{
+    error::Error,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{TcpListener, TcpStream},
+};
+use unsigned_varint::decode;
+
+use crate::auth::{AsyncHandShake, AuthProtocol};
+use crate::identity::Keypair;
+use crate::muxer::MuxerProtocol;
+use crate::peer_id::PeerId;
+
+use super::{
+    multistream::MultistreamError,
+    select::{self, AsyncMessageStream, Negotiator, Version},
+    AsyncConnection, AsyncIncoming, AsyncListener,
+};
+
+/// The async counterpart of [super::multistream::Multistream] built on a [tokio::net::TcpStream].
+pub struct AsyncMultistream {
+    stream: TcpStream,
+    initiator: bool,
+    /// Negotiation messages of a [Version::V1Lazy] dial that go out with the first write
+    unsent: Vec<u8>,
+    /// The protocol of a [Version::V1Lazy] dial that the listener has not confirmed yet
+    lazy: Option<String>,
+    /// Bytes read along with the answer to a lazy dial that belong to the data after it
+    buffer: Vec<u8>,
+}
+
+impl AsyncConnection for AsyncMultistream {
+    type Listener = AsyncMultistreamListener;
+
+    async fn connect(address: SocketAddr, auth_protocol: AuthProtocol) -> Result<Self, Box<dyn Error>> {
+        Self::connect_with_version(address, auth_protocol, Version::V1).await
+    }
+
+    async fn upgrade<H: AsyncHandShake<Self>>(
+        connection: Self,
+        peer_id: Keypair,
+        remote_peer: Option<PeerId>,
+        muxers: &[MuxerProtocol],
+    ) -> Result<H::Channel, Box<dyn Error>> {
+        let initiator = connection.initiator;
+        H::upgrade(connection, peer_id, remote_peer, initiator, muxers).await
+    }
+}
+
+impl AsyncMultistream {
+    /// Wraps a [TcpStream], `initiator` should be true if the local peer dialed the stream
+    /// and false if it was accepted from a remote peer.
+    pub fn new(stream: TcpStream, initiator: bool) -> Self {
+        AsyncMultistream {
+            stream,
+            initiator,
+            unsent: Vec::new(),
+            lazy: None,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Connect to a remote peer negotiating with the chosen multistream-select [Version].
+    ///
+    /// With [Version::V1Lazy] this returns without waiting on the peer, the negotiation is sent with the
+    /// first handshake message and a late `na` fails the first read with an [io::Error] wrapping
+    /// [MultistreamError::Rejected].
+    pub async fn connect_with_version(
+        address: SocketAddr,
+        auth_protocol: AuthProtocol,
+        version: Version,
+    ) -> Result<Self, Box<dyn Error>> {
+        let stream = TcpStream::connect(address).await?;
+        let mut connection = Self::new(stream, true);
+        match version {
+            Version::V1 => {
+                select::negotiate_async(&mut connection, Negotiator::dial(&[auth_protocol.protocol()])).await?;
+            }
+            Version::V1Lazy => {
+                connection.unsent = select::encode_lazy(auth_protocol.protocol());
+                connection.lazy = Some(auth_protocol.protocol().to_owned());
+            }
+        }
+        Ok(connection)
+    }
+
+    /// Writes the negotiation messages of a lazy dial ahead of anything else.
+    fn poll_send_unsent(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.unsent.is_empty() {
+            let written = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.unsent))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.unsent.drain(..written);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Checks the listener's answer to a lazy dial before anything else is read, the bytes that
+    /// arrive after the answer are kept in `buffer`.
+    fn poll_confirm_lazy(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // The proposal must have been sent for the listener to answer it
+        ready!(self.poll_send_unsent(cx))?;
+        loop {
+            let Some(protocol) = &self.lazy else {
+                return Poll::Ready(Ok(()));
+            };
+            match confirm_buffered(&self.buffer, protocol) {
+                Ok(Some(len)) => {
+                    self.buffer.drain(..len);
+                    self.lazy = None;
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(None) => {}
+                Err(error) => return Poll::Ready(Err(into_io_error(error))),
+            }
+            let mut chunk = [0u8; 1024];
+            let mut read = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut self.stream).poll_read(cx, &mut read))?;
+            if read.filled().is_empty() {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            self.buffer.extend_from_slice(read.filled());
+        }
+    }
+}
+
+impl AsyncMessageStream for AsyncMultistream {
+    async fn read_message(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+        read_message(&mut self.stream).await
+    }
+
+    async fn write_message(&mut self, message: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.stream.write_all(&select::encode_message(message)).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+}
+
+impl AsyncRead for AsyncMultistream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        ready!(self.poll_confirm_lazy(cx))?;
+        if !self.buffer.is_empty() {
+            let len = self.buffer.len().min(buf.remaining());
+            buf.put_slice(&self.buffer[..len]);
+            self.buffer.drain(..len);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for AsyncMultistream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.poll_send_unsent(cx))?;
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_send_unsent(cx))?;
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_send_unsent(cx))?;
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Runs the answer to a lazy dial through [Negotiator::confirm] once it is complete in `buffer`, returns
+/// the number of bytes it took up or [None] while more are needed. Confirming never sends anything.
+fn confirm_buffered(buffer: &[u8], protocol: &str) -> Result<Option<usize>, Box<dyn Error>> {
+    let mut negotiator = Negotiator::confirm(protocol);
+    let mut offset = 0;
+    while let Some((message, len)) = select::decode_message(&buffer[offset..])? {
+        offset += len;
+        if negotiator.receive(&message)?.outcome.is_some() {
+            return Ok(Some(offset));
+        }
+    }
+    Ok(None)
+}
+
+/// Keeps a negotiation error such as [MultistreamError::Rejected] inspectable through the [io::Error]
+/// it fails a read with.
+fn into_io_error(error: Box<dyn Error>) -> io::Error {
+    match error.downcast::<MultistreamError>() {
+        Ok(error) => io::Error::new(io::ErrorKind::InvalidData, *error),
+        Err(error) => io::Error::new(io::ErrorKind::InvalidData, error.to_string()),
+    }
+}
+
+/// Reads a single varint length-prefixed multistream message without its trailing newline, the length is checked
+/// against [select::MAX_MESSAGE_LEN] before the message is buffered.
+async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut line = Vec::new();
+    loop {
+        if line.len() == select::MAX_LENGTH_PREFIX {
+            return Err(MultistreamError::MessageTooLong().into());
+        }
+        let byte = reader.read_u8().await?;
+        line.push(byte);
+        if decode::is_last(byte) {
+            break;
+        }
+    }
+    let (msg_len, _) = decode::usize(&line)?;
+    if msg_len > select::MAX_MESSAGE_LEN {
+        return Err(MultistreamError::MessageTooLong().into());
+    }
+    let prefix_len = line.len();
+    line.resize(prefix_len + msg_len, 0);
+    reader.read_exact(&mut line[prefix_len..]).await?;
+    let (message, _) = select::decode_message(&line)?.ok_or(MultistreamError::Negotiation())?;
+    Ok(message)
+}
+
+/// Accepts inbound [AsyncMultistream] connections on a bound [SocketAddr].
+pub struct AsyncMultistreamListener {
+    listener: TcpListener,
+    auth_protocol: AuthProtocol,
+}
+
+/// A TCP connection accepted by an [AsyncMultistreamListener] that has not negotiated yet.
+pub struct AsyncIncomingMultistream {
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    auth_protocol: AuthProtocol,
+}
+
+impl AsyncIncoming for AsyncIncomingMultistream {
+    type Connection = AsyncMultistream;
+
+    /// Answers the `/multistream/1.0.0` negotiation from the listener side, rejecting every
+    /// proposed protocol with `na` until the dialer proposes the auth protocol.
+    async fn negotiate(self) -> Result<AsyncMultistream, Box<dyn Error>> {
+        let mut connection = AsyncMultistream::new(self.stream, false);
+        select::negotiate_async(&mut connection, Negotiator::listen(&[self.auth_protocol.protocol()])).await?;
+        Ok(connection)
+    }
+
+    fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+}
+
+impl AsyncListener for AsyncMultistreamListener {
+    type Connection = AsyncMultistream;
+    type Incoming = AsyncIncomingMultistream;
+
+    async fn bind(address: SocketAddr, auth_protocol: AuthProtocol) -> Result<Self, Box<dyn Error>> {
+        let listener = TcpListener::bind(address).await?;
+        Ok(AsyncMultistreamListener { listener, auth_protocol })
+    }
+
+    async fn accept(&self) -> Result<AsyncIncomingMultistream, Box<dyn Error>> {
+        let (stream, peer_addr) = self.listener.accept().await?;
+        Ok(AsyncIncomingMultistream {
+            stream,
+            peer_addr,
+            auth_protocol: self.auth_protocol,
+        })
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, Box<dyn Error>> {
+        Ok(self.listener.local_addr()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::identity::Keypair;
+
+    use super::{read_message, AsyncMultistream};
+    use crate::auth::{
+        noise::{
+            handshake_state::MAX_MESSAGE_LEN,
+            protocol::{NoiseError, NoiseProtocol},
+        },
+        AsyncSecureChannel, AuthProtocol,
+    };
+    use crate::connection::{
+        multistream::MultistreamError,
+        select::{self, Negotiator, Outcome, Version},
+        AsyncConnection, AsyncIncoming, AsyncListener,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_async_loopback() {
+        let listener = AsyncMultistream::listen("127.0.0.1:0".parse().unwrap(), AuthProtocol::Noise)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let dialer = tokio::spawn(async move {
+            let connection = AsyncMultistream::connect(addr, AuthProtocol::Noise).await.unwrap();
+            let keypair = Keypair::generate_ed25519();
+            let mut channel = AsyncMultistream::upgrade::<NoiseProtocol>(connection, keypair, None, &[])
+                .await
+                .unwrap();
+            // An oversized message is refused before it is encrypted, so the channel stays usable
+            let too_long = match channel.write(&vec![0; MAX_MESSAGE_LEN]).await {
+                Err(error) => matches!(error.downcast_ref(), Some(NoiseError::FrameTooLong(_))),
+                Ok(()) => false,
+            };
+            assert!(too_long);
+            channel.write(b"ping").await.unwrap();
+            assert_eq!(channel.read().await.unwrap(), b"pong");
+        });
+
+        let connection = listener.accept().await.unwrap().negotiate().await.unwrap();
+        let keypair = Keypair::generate_ed25519();
+        let mut channel = AsyncMultistream::upgrade::<NoiseProtocol>(connection, keypair, None, &[])
+            .await
+            .unwrap();
+        assert_eq!(channel.read().await.unwrap(), b"ping");
+        channel.write(b"pong").await.unwrap();
+        dialer.await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_idle_peer_does_not_block_accept() {
+        let listener = AsyncMultistream::listen("127.0.0.1:0".parse().unwrap(), AuthProtocol::Noise)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Connects first but never sends the multistream header
+        let idle = TcpStream::connect(addr).await.unwrap();
+        let dialer = tokio::spawn(async move {
+            let connection = AsyncMultistream::connect(addr, AuthProtocol::Noise).await.unwrap();
+            let keypair = Keypair::generate_ed25519();
+            let mut channel = AsyncMultistream::upgrade::<NoiseProtocol>(connection, keypair, None, &[])
+                .await
+                .unwrap();
+            channel.write(b"hello listener").await.unwrap();
+        });
+
+        let incoming = listener.accept().await.unwrap();
+        assert_eq!(incoming.peer_addr(), idle.local_addr().unwrap());
+        let pending = tokio::spawn(async move { incoming.negotiate().await.is_ok() });
+
+        let connection = listener.accept().await.unwrap().negotiate().await.unwrap();
+        let keypair = Keypair::generate_ed25519();
+        let mut channel = AsyncMultistream::upgrade::<NoiseProtocol>(connection, keypair, None, &[])
+            .await
+            .unwrap();
+        assert_eq!(channel.read().await.unwrap(), b"hello listener");
+        dialer.await.unwrap();
+
+        // The idle peer's failed negotiation only ends its own task
+        drop(idle);
+        assert!(!pending.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_bounded_messages() {
+        // A zero-length frame has no newline to strip
+        let error = read_message(&mut &[0u8][..]).await.err().unwrap();
+        assert!(matches!(error.downcast_ref(), Some(MultistreamError::MissingNewline())));
+        let error = read_message(&mut &[0xffu8; 8][..]).await.err().unwrap();
+        assert!(matches!(error.downcast_ref(), Some(MultistreamError::MessageTooLong())));
+        let oversized = select::encode_message(&vec![b'a'; select::MAX_MESSAGE_LEN]);
+        let error = read_message(&mut &oversized[..]).await.err().unwrap();
+        assert!(matches!(error.downcast_ref(), Some(MultistreamError::MessageTooLong())));
+
+        let message = select::encode_message(b"/noise");
+        assert_eq!(read_message(&mut &message[..]).await.unwrap(), b"/noise");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_async_lazy_dial() {
+        let listener = AsyncMultistream::listen("127.0.0.1:0".parse().unwrap(), AuthProtocol::Noise)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let dialer = tokio::spawn(async move {
+            let connection = AsyncMultistream::connect_with_version(addr, AuthProtocol::Noise, Version::V1Lazy)
+                .await
+                .unwrap();
+            let keypair = Keypair::generate_ed25519();
+            let mut channel = AsyncMultistream::upgrade::<NoiseProtocol>(connection, keypair, None, &[])
+                .await
+                .unwrap();
+            channel.write(b"hello lazy listener").await.unwrap();
+        });
+
+        let connection = listener.accept().await.unwrap().negotiate().await.unwrap();
+        let keypair = Keypair::generate_ed25519();
+        let mut channel = AsyncMultistream::upgrade::<NoiseProtocol>(connection, keypair, None, &[])
+            .await
+            .unwrap();
+        assert_eq!(channel.read().await.unwrap(), b"hello lazy listener");
+        dialer.await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_async_lazy_dial_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // The listener only speaks a different protocol so it answers the lazy proposal with na
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut connection = AsyncMultistream::new(stream, false);
+            let _ = select::negotiate_async(&mut connection, Negotiator::listen(&["/tls/1.0.0"])).await;
+        });
+
+        let mut connection = AsyncMultistream::connect_with_version(addr, AuthProtocol::Noise, Version::V1Lazy)
+            .await
+            .unwrap();
+        connection.write_all(b"first handshake message").await.unwrap();
+        let error = connection.read_u8().await.unwrap_err();
+        let error = error.get_ref().and_then(|error| error.downcast_ref::<MultistreamError>());
+        assert!(matches!(error, Some(MultistreamError::Rejected(_))));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_async_list_and_propose() {
+        let listener = AsyncMultistream::listen("127.0.0.1:0".parse().unwrap(), AuthProtocol::Noise)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let dialer = tokio::spawn(async move {
+            let mut connection = AsyncMultistream::new(TcpStream::connect(addr).await.unwrap(), true);
+            let listing = select::negotiate_async(&mut connection, Negotiator::list()).await.unwrap();
+            assert_eq!(listing, Outcome::Listing(vec!["/noise".to_owned()]));
+            let protocols = ["/tls/1.0.0", "/noise"];
+            let protocol = select::negotiate_async(&mut connection, Negotiator::propose(&protocols)).await.unwrap();
+            assert_eq!(protocol, Outcome::Protocol("/noise".to_owned()));
+        });
+
+        listener.accept().await.unwrap().negotiate().await.unwrap();
+        dialer.await.unwrap();
+    }
+}
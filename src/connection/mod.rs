@@ -1,21 +1,159 @@
+pub mod async_multistream;
 pub mod multistream;
-use std::{error::Error, net::SocketAddr};
+pub mod pnet;
+pub mod select;
+use std::{
+    error::Error,
+    future::Future,
+    io::{Read, Write},
+    net::SocketAddr,
+};
 
-use ed25519_dalek::Keypair;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::auth::{AsyncHandShake, HandShake, SecureChannel, AuthProtocol};
+use crate::identity::Keypair;
+use crate::muxer::MuxerProtocol;
+use crate::peer_id::PeerId;
+/// A blocking byte stream a [Connection] can be built on, such as a [std::net::TcpStream]
+/// or a [pnet::PnetStream] wrapping one.
+pub trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
 
-use crate::auth::{HandShake, SecureChannel, AuthProtocol};
 pub trait Connection {
+    /// The [Listener] that accepts inbound connections of this type
+    type Listener: Listener<Connection = Self>;
 
     /// Connect to a remote peer using their [std::net::SocketAddr]
     fn connect(address: SocketAddr, auth_protocol: AuthProtocol) -> Result<Self, Box<dyn Error>>
     where
         Self: Sized;
 
-    /// Upgrade the connection to a [SecureChannel] for communication
+    /// Listen for inbound connections from remote peers on a local [std::net::SocketAddr]
+    fn listen(address: SocketAddr, auth_protocol: AuthProtocol) -> Result<Self::Listener, Box<dyn Error>>
+    where
+        Self: Sized,
+    {
+        Self::Listener::bind(address, auth_protocol)
+    }
+
+    /// Upgrade the connection to a [SecureChannel] for communication, advertising `muxers` during the handshake.
+    /// When `remote_peer` is set the remote must authenticate as that peer, see [HandShake::upgrade].
     fn upgrade<'a, H: HandShake<'a, Self> + 'a>(
         connection: Self,
         peer_id: Keypair,
+        remote_peer: Option<PeerId>,
+        muxers: &[MuxerProtocol],
     ) -> Result<Box<dyn SecureChannel + 'a>, Box<dyn Error>>
     where
         Self: Sized + 'a;
 }
+
+pub trait Listener {
+    /// The [Connection] produced for every accepted remote peer
+    type Connection: Connection;
+
+    /// An accepted remote peer that has not negotiated the auth protocol yet
+    type Incoming: Incoming<Connection = Self::Connection>;
+
+    /// Bind to a local [std::net::SocketAddr], accepted peers must negotiate `auth_protocol`
+    fn bind(address: SocketAddr, auth_protocol: AuthProtocol) -> Result<Self, Box<dyn Error>>
+    where
+        Self: Sized;
+
+    /// Block until a remote peer connects. Nothing has been read from the peer yet, so a slow or silent
+    /// peer cannot hold up the next `accept` as long as [Incoming::negotiate] runs on another thread.
+    fn accept(&self) -> Result<Self::Incoming, Box<dyn Error>>;
+
+    /// The local [std::net::SocketAddr] the listener is bound to
+    fn local_addr(&self) -> Result<SocketAddr, Box<dyn Error>>;
+}
+
+/// A remote peer handed out by [Listener::accept] before any protocol negotiation, it is `Send` and
+/// `'static` so it can be moved to a worker thread.
+pub trait Incoming: Send + 'static {
+    /// The [Connection] produced once the negotiation succeeds
+    type Connection: Connection;
+
+    /// Block until the remote peer has agreed on the auth protocol,
+    /// the returned connection is ready for a responder-side upgrade.
+    fn negotiate(self) -> Result<Self::Connection, Box<dyn Error>>;
+
+    /// The [std::net::SocketAddr] of the remote peer
+    fn peer_addr(&self) -> SocketAddr;
+}
+
+/// The async counterpart of [Connection], an [AsyncConnection] exposes its underlying byte stream
+/// so an [AsyncHandShake] can frame its messages without blocking a thread.
+pub trait AsyncConnection: AsyncRead + AsyncWrite + Unpin + Send {
+    /// The [AsyncListener] that accepts inbound connections of this type
+    type Listener: AsyncListener<Connection = Self>;
+
+    /// Connect to a remote peer using their [std::net::SocketAddr]
+    fn connect(
+        address: SocketAddr,
+        auth_protocol: AuthProtocol,
+    ) -> impl Future<Output = Result<Self, Box<dyn Error>>> + Send
+    where
+        Self: Sized;
+
+    /// Listen for inbound connections from remote peers on a local [std::net::SocketAddr]
+    fn listen(
+        address: SocketAddr,
+        auth_protocol: AuthProtocol,
+    ) -> impl Future<Output = Result<Self::Listener, Box<dyn Error>>> + Send
+    where
+        Self: Sized,
+    {
+        Self::Listener::bind(address, auth_protocol)
+    }
+
+    /// Upgrade the connection to an [crate::auth::AsyncSecureChannel] for communication,
+    /// the remote must authenticate with `remote_peer` when it is set.
+    fn upgrade<H: AsyncHandShake<Self>>(
+        connection: Self,
+        peer_id: Keypair,
+        remote_peer: Option<PeerId>,
+        muxers: &[MuxerProtocol],
+    ) -> impl Future<Output = Result<H::Channel, Box<dyn Error>>> + Send
+    where
+        Self: Sized;
+}
+
+pub trait AsyncListener {
+    /// The [AsyncConnection] produced for every accepted remote peer
+    type Connection: AsyncConnection;
+
+    /// An accepted remote peer that has not negotiated the auth protocol yet
+    type Incoming: AsyncIncoming<Connection = Self::Connection>;
+
+    /// Bind to a local [std::net::SocketAddr], accepted peers must negotiate `auth_protocol`
+    fn bind(
+        address: SocketAddr,
+        auth_protocol: AuthProtocol,
+    ) -> impl Future<Output = Result<Self, Box<dyn Error>>> + Send
+    where
+        Self: Sized;
+
+    /// Wait until a remote peer connects. The negotiation has not started yet, so a slow or silent peer
+    /// cannot hold up the next `accept` as long as [AsyncIncoming::negotiate] runs on its own task.
+    fn accept(&self) -> impl Future<Output = Result<Self::Incoming, Box<dyn Error>>> + Send;
+
+    /// The local [std::net::SocketAddr] the listener is bound to
+    fn local_addr(&self) -> Result<SocketAddr, Box<dyn Error>>;
+}
+
+/// A remote peer handed out by [AsyncListener::accept] before any protocol negotiation, it is `Send` and
+/// `'static` so it can be moved into a `tokio::spawn`ed task.
+pub trait AsyncIncoming: Send + 'static {
+    /// The [AsyncConnection] produced once the negotiation succeeds
+    type Connection: AsyncConnection;
+
+    /// Wait until the remote peer has agreed on the auth protocol,
+    /// the returned connection is ready for a responder-side upgrade.
+    fn negotiate(self) -> impl Future<Output = Result<Self::Connection, Box<dyn Error>>> + Send;
+
+    /// The [std::net::SocketAddr] of the remote peer
+    fn peer_addr(&self) -> SocketAddr;
+}
@@ -1,14 +1,20 @@
 use std::{
     error::Error,
-    io::{BufRead, BufReader, Read, Write},
-    net::{SocketAddr, TcpStream},
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
 };
 use unsigned_varint::{decode, encode};
 
 use crate::auth::{HandShake, SecureChannel, AuthProtocol};
-use ed25519_dalek::Keypair;
+use crate::identity::Keypair;
+use crate::muxer::MuxerProtocol;
+use crate::peer_id::PeerId;
 
-use super::Connection;
+use super::{
+    pnet::{PnetStream, SwarmKey},
+    select::{self, MessageStream, Version},
+    Connection, Incoming, Listener, Stream,
+};
 
 use thiserror::Error;
 
@@ -18,36 +24,41 @@ pub enum MultistreamError {
     Negotiation(),
     #[error("the peer does not support the supplied auth protocol")]
     Auth(),
+    #[error("the peer supports none of the proposed protocols")]
+    Unsupported(),
+    #[error("expected a protocol or na but the peer sent {0}")]
+    UnexpectedMessage(String),
+    #[error("multistream messages must end with a newline")]
+    MissingNewline(),
+    #[error("the peer rejected the lazily negotiated protocol {0}")]
+    Rejected(String),
+    #[error("multistream messages are limited to {} bytes", select::MAX_MESSAGE_LEN)]
+    MessageTooLong(),
+    #[error("secure frames are limited to {} bytes, a {0} byte message does not fit", u16::MAX)]
+    FrameTooLong(usize),
 }
 
 pub struct Multistream {
-    stream: TcpStream,
+    stream: Box<dyn Stream>,
+    initiator: bool,
+    /// Negotiation messages of a [Version::V1Lazy] dial that go out with the first write
+    unsent: Vec<u8>,
+    /// The protocol of a [Version::V1Lazy] dial that the listener has not confirmed yet
+    lazy: Option<String>,
 }
 
 impl Connection for Multistream {
-    fn connect(address: SocketAddr, auth_protocol: AuthProtocol) -> Result<Self, Box<dyn std::error::Error>> {
-        let stream = TcpStream::connect(address)?;
-        let mut connection = Self::new(stream);
-        connection.write(b"/multistream/1.0.0\n", false)?;
-        let received = connection.read(false)?;
-        let received = Self::deserialize(&received)?;
-        if std::str::from_utf8(&received)? != "/multistream/1.0.0" {
-            return Err(MultistreamError::Negotiation().into());
-        }
+    type Listener = MultistreamListener;
 
-        connection.write(auth_protocol.name(), false)?;
-        let received = connection.read(false)?;
-        let received = Self::deserialize(&received)?;
-        let auth_str = std::str::from_utf8(&auth_protocol.name()[..auth_protocol.name().len() -1])?;
-        if std::str::from_utf8(&received)? != auth_str {
-            return Err(MultistreamError::Auth().into());
-        }
-        Ok(connection)
+    fn connect(address: SocketAddr, auth_protocol: AuthProtocol) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::connect_with_version(address, auth_protocol, Version::V1)
     }
 
     fn upgrade<'a, H: HandShake<'a, Self> + 'a>(
         connection: Self,
         peer_id: Keypair,
+        remote_peer: Option<PeerId>,
+        muxers: &[MuxerProtocol],
     ) -> Result<Box<dyn SecureChannel + 'a>, Box<dyn Error>>
     where
         Self: Sized + 'a,
@@ -58,30 +69,90 @@ impl Connection for Multistream {
             x.write(data, true)?;
             Ok(())
         };
-        Ok(Box::new(H::upgrade(connection, peer_id, reader, writer)?))
+        let initiator = connection.initiator;
+        Ok(Box::new(H::upgrade(connection, peer_id, remote_peer, initiator, muxers, reader, writer)?))
     }
 }
 
 impl Multistream {
-    pub fn new(stream: TcpStream) -> Self {
-        Multistream { stream }
+    /// Wraps a byte [Stream], `initiator` should be true if the local peer dialed the stream
+    /// and false if it was accepted from a remote peer.
+    pub fn new<S: Stream + 'static>(stream: S, initiator: bool) -> Self {
+        Multistream {
+            stream: Box::new(stream),
+            initiator,
+            unsent: Vec::new(),
+            lazy: None,
+        }
+    }
+
+    /// Connect to a remote peer negotiating with the chosen multistream-select [Version].
+    ///
+    /// With [Version::V1Lazy] this returns without waiting on the peer, the negotiation is sent with the
+    /// first handshake message and a late `na` surfaces as [MultistreamError::Rejected] from the first read.
+    pub fn connect_with_version(
+        address: SocketAddr,
+        auth_protocol: AuthProtocol,
+        version: Version,
+    ) -> Result<Self, Box<dyn Error>> {
+        let stream = TcpStream::connect(address)?;
+        Self::dial(Self::new(stream, true), auth_protocol, version)
+    }
+
+    /// Connect to a remote peer in a libp2p private network, the TCP stream is encrypted with
+    /// `swarm_key` before multistream-select and the auth handshake take place.
+    pub fn connect_private(
+        address: SocketAddr,
+        auth_protocol: AuthProtocol,
+        swarm_key: &SwarmKey,
+    ) -> Result<Self, Box<dyn Error>> {
+        let stream = PnetStream::new(TcpStream::connect(address)?, swarm_key)?;
+        Self::dial(Self::new(stream, true), auth_protocol, Version::V1)
     }
+
+    /// Negotiates `/multistream/1.0.0` and `auth_protocol` from the dialer side.
+    fn dial(mut connection: Self, auth_protocol: AuthProtocol, version: Version) -> Result<Self, Box<dyn Error>> {
+        match version {
+            Version::V1 => {
+                select::dial(&mut connection, &[auth_protocol.protocol()])?;
+            }
+            Version::V1Lazy => {
+                connection.unsent = select::encode_lazy(auth_protocol.protocol());
+                connection.lazy = Some(auth_protocol.protocol().to_owned());
+            }
+        }
+        Ok(connection)
+    }
+
+    /// Writes `bytes` to the stream, preceded by any negotiation messages of a lazy dial.
+    fn send(&mut self, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+        let unsent = std::mem::take(&mut self.unsent);
+        self.stream.write_all(&[&unsent[..], bytes].concat())?;
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    /// Checks the listener's answer to a lazy dial before anything else is read.
+    fn confirm_lazy(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(protocol) = self.lazy.take() {
+            // The proposal must have been sent for the listener to answer it
+            self.send(&[])?;
+            select::confirm(self, &protocol)?;
+        }
+        Ok(())
+    }
+
     fn write(&mut self, message: &[u8], secure: bool) -> Result<(), Box<dyn Error>> {
         if secure {
-            let data_len = (message.len() as u16).to_be_bytes();
-            let payload = [&data_len[..], message].concat();
-            self.stream.write_all(&payload)?;
-            self.stream.flush()?;
-            Ok(())
+            let data_len = u16::try_from(message.len()).map_err(|_| MultistreamError::FrameTooLong(message.len()))?;
+            self.send(&[&data_len.to_be_bytes()[..], message].concat())
         } else {
-            let msg = Self::serialize(message);
-            self.stream.write_all(&msg)?;
-            self.stream.flush()?;
-            Ok(())
+            self.send(&Self::serialize(message))
         }
     }
 
     fn read(&mut self, secure: bool) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.confirm_lazy()?;
         if secure {
             let mut msg_len = [0u8; 2];
             self.stream.read_exact(&mut msg_len)?;
@@ -90,10 +161,27 @@ impl Multistream {
             self.stream.read_exact(&mut encrypted)?;
             Ok(encrypted)
         } else {
-            let mut reader = BufReader::new(self.stream.try_clone()?);
-            let mut line = String::new();
-            reader.read_line(&mut line)?;
-            Ok(line.as_bytes().to_vec())
+            // Read the varint prefix one byte at a time so no bytes past this message are consumed
+            let mut line = Vec::new();
+            let mut byte = [0u8; 1];
+            loop {
+                if line.len() == select::MAX_LENGTH_PREFIX {
+                    return Err(MultistreamError::MessageTooLong().into());
+                }
+                self.stream.read_exact(&mut byte)?;
+                line.push(byte[0]);
+                if decode::is_last(byte[0]) {
+                    break;
+                }
+            }
+            let (msg_len, _) = decode::usize(&line)?;
+            if msg_len > select::MAX_MESSAGE_LEN {
+                return Err(MultistreamError::MessageTooLong().into());
+            }
+            let mut message = vec![0u8; msg_len];
+            self.stream.read_exact(&mut message)?;
+            line.append(&mut message);
+            Ok(line)
         }
     }
 
@@ -109,3 +197,240 @@ impl Multistream {
     }
 }
 
+impl MessageStream for Multistream {
+    fn read_message(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let line = self.read(false)?;
+        let (message, _) = select::decode_message(&line)?.ok_or(MultistreamError::Negotiation())?;
+        Ok(message)
+    }
+
+    fn write_message(&mut self, message: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.send(&select::encode_message(message))
+    }
+}
+
+/// Accepts inbound [Multistream] connections on a bound [SocketAddr].
+pub struct MultistreamListener {
+    listener: TcpListener,
+    auth_protocol: AuthProtocol,
+    swarm_key: Option<SwarmKey>,
+}
+
+/// A TCP connection accepted by a [MultistreamListener] that has not negotiated yet.
+pub struct IncomingMultistream {
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    auth_protocol: AuthProtocol,
+    swarm_key: Option<SwarmKey>,
+}
+
+impl Incoming for IncomingMultistream {
+    type Connection = Multistream;
+
+    /// Exchanges the pnet nonces when the listener has a swarm key, then answers the `/multistream/1.0.0`
+    /// negotiation from the listener side, rejecting every proposed protocol with `na` until the dialer
+    /// proposes the auth protocol.
+    fn negotiate(self) -> Result<Multistream, Box<dyn Error>> {
+        let mut connection = match &self.swarm_key {
+            Some(swarm_key) => Multistream::new(PnetStream::new(self.stream, swarm_key)?, false),
+            None => Multistream::new(self.stream, false),
+        };
+        select::listen(&mut connection, &[self.auth_protocol.protocol()])?;
+        Ok(connection)
+    }
+
+    fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+}
+
+impl MultistreamListener {
+    /// Only accept peers in the libp2p private network protected by `swarm_key`.
+    pub fn with_swarm_key(mut self, swarm_key: SwarmKey) -> Self {
+        self.swarm_key = Some(swarm_key);
+        self
+    }
+}
+
+impl Listener for MultistreamListener {
+    type Connection = Multistream;
+    type Incoming = IncomingMultistream;
+
+    fn bind(address: SocketAddr, auth_protocol: AuthProtocol) -> Result<Self, Box<dyn Error>> {
+        let listener = TcpListener::bind(address)?;
+        Ok(MultistreamListener {
+            listener,
+            auth_protocol,
+            swarm_key: None,
+        })
+    }
+
+    fn accept(&self) -> Result<IncomingMultistream, Box<dyn Error>> {
+        let (stream, peer_addr) = self.listener.accept()?;
+        Ok(IncomingMultistream {
+            stream,
+            peer_addr,
+            auth_protocol: self.auth_protocol,
+            swarm_key: self.swarm_key.clone(),
+        })
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, Box<dyn Error>> {
+        Ok(self.listener.local_addr()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, net::TcpStream, thread, time::Duration};
+
+    use crate::identity::Keypair;
+
+    use super::{Multistream, MultistreamError};
+    use crate::auth::{noise::protocol::NoiseProtocol, AuthProtocol};
+    use crate::connection::{
+        pnet::{PnetStream, SwarmKey},
+        select::{self, Version},
+        Connection, Incoming, Listener,
+    };
+
+    #[test]
+    fn test_listener_accept() {
+        let listener = Multistream::listen("127.0.0.1:0".parse().unwrap(), AuthProtocol::Noise).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let dialer = thread::spawn(move || {
+            let connection = Multistream::connect(addr, AuthProtocol::Noise).unwrap();
+            let keypair = Keypair::generate_ed25519();
+            let mut channel = Multistream::upgrade::<NoiseProtocol>(connection, keypair, None, &[]).unwrap();
+            channel.write(b"hello listener").unwrap();
+        });
+
+        let connection = listener.accept().unwrap().negotiate().unwrap();
+        let keypair = Keypair::generate_ed25519();
+        let mut channel = Multistream::upgrade::<NoiseProtocol>(connection, keypair, None, &[]).unwrap();
+        assert_eq!(channel.read().unwrap(), b"hello listener");
+        dialer.join().unwrap();
+    }
+
+    #[test]
+    fn test_private_network() {
+        let swarm_key = SwarmKey::new([42u8; 32]);
+        let listener = Multistream::listen("127.0.0.1:0".parse().unwrap(), AuthProtocol::Noise)
+            .unwrap()
+            .with_swarm_key(swarm_key.clone());
+        let addr = listener.local_addr().unwrap();
+
+        let dialer = thread::spawn(move || {
+            let connection = Multistream::connect_private(addr, AuthProtocol::Noise, &swarm_key).unwrap();
+            let keypair = Keypair::generate_ed25519();
+            let mut channel = Multistream::upgrade::<NoiseProtocol>(connection, keypair, None, &[]).unwrap();
+            channel.write(b"hello private network").unwrap();
+        });
+
+        let connection = listener.accept().unwrap().negotiate().unwrap();
+        let keypair = Keypair::generate_ed25519();
+        let mut channel = Multistream::upgrade::<NoiseProtocol>(connection, keypair, None, &[]).unwrap();
+        assert_eq!(channel.read().unwrap(), b"hello private network");
+        dialer.join().unwrap();
+
+        // A peer with a different swarm key only ever sees the other's bytes as noise
+        let outsider = thread::spawn(move || {
+            let stream = TcpStream::connect(addr).unwrap();
+            // Garbage can decode as a length prefix neither side has the bytes for, the timeout ends the wait
+            stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+            let stream = PnetStream::new(stream, &SwarmKey::new([7u8; 32])).unwrap();
+            assert!(select::dial(&mut Multistream::new(stream, true), &["/noise"]).is_err());
+        });
+        let upgrade = listener.accept().unwrap().negotiate().and_then(|connection| {
+            Multistream::upgrade::<NoiseProtocol>(connection, Keypair::generate_ed25519(), None, &[]).map(|_| ())
+        });
+        assert!(upgrade.is_err());
+        outsider.join().unwrap();
+    }
+
+    #[test]
+    fn test_idle_peer_does_not_block_accept() {
+        let swarm_key = SwarmKey::new([42u8; 32]);
+        let listener = Multistream::listen("127.0.0.1:0".parse().unwrap(), AuthProtocol::Noise)
+            .unwrap()
+            .with_swarm_key(swarm_key.clone());
+        let addr = listener.local_addr().unwrap();
+
+        // Connects first but never sends its pnet nonce
+        let idle = TcpStream::connect(addr).unwrap();
+        let dialer = thread::spawn(move || {
+            let connection = Multistream::connect_private(addr, AuthProtocol::Noise, &swarm_key).unwrap();
+            let keypair = Keypair::generate_ed25519();
+            let mut channel = Multistream::upgrade::<NoiseProtocol>(connection, keypair, None, &[]).unwrap();
+            channel.write(b"hello listener").unwrap();
+        });
+
+        let incoming = listener.accept().unwrap();
+        assert_eq!(incoming.peer_addr(), idle.local_addr().unwrap());
+        let pending = thread::spawn(move || incoming.negotiate().is_ok());
+
+        let connection = listener.accept().unwrap().negotiate().unwrap();
+        let keypair = Keypair::generate_ed25519();
+        let mut channel = Multistream::upgrade::<NoiseProtocol>(connection, keypair, None, &[]).unwrap();
+        assert_eq!(channel.read().unwrap(), b"hello listener");
+        dialer.join().unwrap();
+
+        // The idle peer's failed negotiation only ends its own thread
+        drop(idle);
+        assert!(!pending.join().unwrap());
+    }
+
+    #[test]
+    fn test_lazy_dial() {
+        let listener = Multistream::listen("127.0.0.1:0".parse().unwrap(), AuthProtocol::Noise).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let dialer = thread::spawn(move || {
+            let connection = Multistream::connect_with_version(addr, AuthProtocol::Noise, Version::V1Lazy).unwrap();
+            let keypair = Keypair::generate_ed25519();
+            let mut channel = Multistream::upgrade::<NoiseProtocol>(connection, keypair, None, &[]).unwrap();
+            channel.write(b"hello lazy listener").unwrap();
+        });
+
+        let connection = listener.accept().unwrap().negotiate().unwrap();
+        let keypair = Keypair::generate_ed25519();
+        let mut channel = Multistream::upgrade::<NoiseProtocol>(connection, keypair, None, &[]).unwrap();
+        assert_eq!(channel.read().unwrap(), b"hello lazy listener");
+        dialer.join().unwrap();
+    }
+
+    #[test]
+    fn test_lazy_dial_rejected() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // The listener only speaks a different protocol so it answers the lazy proposal with na
+        let remote = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut connection = Multistream::new(stream, false);
+            assert!(select::listen(&mut connection, &["/tls/1.0.0"]).is_err());
+        });
+
+        let mut connection = Multistream::connect_with_version(addr, AuthProtocol::Noise, Version::V1Lazy).unwrap();
+        connection.write(b"first handshake message", true).unwrap();
+        let error = connection.read(true).unwrap_err();
+        assert!(matches!(error.downcast_ref::<MultistreamError>(), Some(MultistreamError::Rejected(_))));
+        drop(connection);
+        remote.join().unwrap();
+    }
+
+    #[test]
+    fn test_oversized_messages() {
+        let too_long = |bytes: Vec<u8>| {
+            let error = Multistream::new(Cursor::new(bytes), false).read(false).unwrap_err();
+            matches!(error.downcast_ref::<MultistreamError>(), Some(MultistreamError::MessageTooLong()))
+        };
+        // A length just over the limit, and a varint prefix longer than any allowed length needs
+        assert!(too_long(Multistream::serialize(&vec![0u8; select::MAX_MESSAGE_LEN + 1])));
+        assert!(too_long(vec![0xff; 16]));
+
+        let message = select::encode_message(&vec![b'a'; select::MAX_MESSAGE_LEN - 1]);
+        assert_eq!(Multistream::new(Cursor::new(message.clone()), false).read(false).unwrap(), message);
+    }
+}
@@ -0,0 +1,189 @@
+use std::{
+    error::Error,
+    fs,
+    io::{self, Read, Write},
+    path::Path,
+};
+
+use multibase::Base;
+use rand::{rngs::OsRng, RngCore};
+use salsa20::{
+    cipher::{KeyIvInit, StreamCipher},
+    XSalsa20,
+};
+use thiserror::Error;
+
+const KEY_HEADER: &str = "/key/swarm/psk/1.0.0/";
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+
+#[derive(Error, Debug)]
+pub enum PnetError {
+    #[error("swarm key does not start with /key/swarm/psk/1.0.0/")]
+    InvalidHeader(),
+    #[error("swarm key encoding {0} is not supported")]
+    UnsupportedEncoding(String),
+    #[error("swarm key must be 32 bytes")]
+    InvalidKey(),
+    #[error("swarm key is not valid base16")]
+    InvalidBase16(),
+    #[error("swarm key is not valid padded base64")]
+    InvalidBase64(),
+}
+
+/// A libp2p private network pre-shared key, as stored in a `swarm.key` file:
+///
+/// ```text
+/// /key/swarm/psk/1.0.0/
+/// /base16/
+/// <64 hex characters>
+/// ```
+///
+/// See the libp2p pnet spec [here](https://github.com/libp2p/specs/blob/master/pnet/Private-Networks-PSK-V1.md).
+#[derive(Clone)]
+pub struct SwarmKey([u8; KEY_LEN]);
+
+impl SwarmKey {
+    pub fn new(key: [u8; KEY_LEN]) -> Self {
+        SwarmKey(key)
+    }
+
+    /// Reads and parses a `swarm.key` file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        Self::decode(&fs::read(path)?)
+    }
+
+    /// Parses the contents of a `swarm.key` file, the `/base16/`, `/base64/` (padded, as go-libp2p writes it)
+    /// and `/bin/` encodings are supported.
+    pub fn decode(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let mut parts = data.splitn(3, |byte| *byte == b'\n');
+        let header = parts.next().unwrap_or_default();
+        if std::str::from_utf8(header)?.trim_end() != KEY_HEADER {
+            return Err(PnetError::InvalidHeader().into());
+        }
+        let encoding = std::str::from_utf8(parts.next().unwrap_or_default())?.trim_end();
+        let body = parts.next().unwrap_or_default();
+
+        let key = match encoding {
+            "/base16/" => {
+                let hex = std::str::from_utf8(body)?.trim();
+                if hex.len() != KEY_LEN * 2 {
+                    return Err(PnetError::InvalidKey().into());
+                }
+                hex.as_bytes()
+                    .chunks(2)
+                    .map(|pair| match pair {
+                        [high, low] if high.is_ascii_hexdigit() && low.is_ascii_hexdigit() => {
+                            Ok((hex_value(*high) << 4) | hex_value(*low))
+                        }
+                        _ => Err(PnetError::InvalidBase16()),
+                    })
+                    .collect::<Result<Vec<u8>, _>>()?
+            }
+            "/base64/" => {
+                let base64 = std::str::from_utf8(body)?.trim();
+                Base::Base64Pad.decode(base64).map_err(|_| PnetError::InvalidBase64())?
+            }
+            "/bin/" => body.to_vec(),
+            _ => return Err(PnetError::UnsupportedEncoding(encoding.to_owned()).into()),
+        };
+        let key: [u8; KEY_LEN] = key.try_into().map_err(|_| PnetError::InvalidKey())?;
+        Ok(SwarmKey(key))
+    }
+}
+
+/// Value of an ASCII hex digit, callers check `is_ascii_hexdigit` first.
+fn hex_value(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        b'a'..=b'f' => digit - b'a' + 10,
+        _ => digit - b'A' + 10,
+    }
+}
+
+/// Encrypts a byte stream with XSalsa20 keyed by a [SwarmKey].
+///
+/// Each side sends a random 24 byte nonce in the clear, then everything written is encrypted
+/// under the local nonce and everything read is decrypted under the remote nonce.
+pub struct PnetStream<S: Read + Write> {
+    stream: S,
+    encrypter: XSalsa20,
+    decrypter: XSalsa20,
+}
+
+impl<S: Read + Write> PnetStream<S> {
+    /// Exchanges nonces with the remote peer and wraps `stream`, this blocks until the remote nonce is read.
+    pub fn new(mut stream: S, swarm_key: &SwarmKey) -> Result<Self, Box<dyn Error>> {
+        let mut local_nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut local_nonce);
+        stream.write_all(&local_nonce)?;
+        stream.flush()?;
+
+        let mut remote_nonce = [0u8; NONCE_LEN];
+        stream.read_exact(&mut remote_nonce)?;
+
+        Ok(PnetStream {
+            stream,
+            encrypter: XSalsa20::new(&swarm_key.0.into(), &local_nonce.into()),
+            decrypter: XSalsa20::new(&swarm_key.0.into(), &remote_nonce.into()),
+        })
+    }
+}
+
+impl<S: Read + Write> Read for PnetStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.stream.read(buf)?;
+        self.decrypter.apply_keystream(&mut buf[..read]);
+        Ok(read)
+    }
+}
+
+impl<S: Read + Write> Write for PnetStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // The keystream has advanced over the whole buffer so all of it must be written
+        let mut encrypted = buf.to_vec();
+        self.encrypter.apply_keystream(&mut encrypted);
+        self.stream.write_all(&encrypted)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PnetError, SwarmKey};
+
+    #[test]
+    fn test_decode_swarm_key() {
+        let hex = "0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f20";
+        let key = SwarmKey::decode(format!("/key/swarm/psk/1.0.0/\n/base16/\n{hex}\n").as_bytes()).unwrap();
+        assert_eq!(key.0, core::array::from_fn::<u8, 32, _>(|index| index as u8 + 1));
+
+        let base64 = "AQIDBAUGBwgJCgsMDQ4PEBESExQVFhcYGRobHB0eHyA=";
+        let key = SwarmKey::decode(format!("/key/swarm/psk/1.0.0/\n/base64/\n{base64}\n").as_bytes()).unwrap();
+        assert_eq!(key.0, core::array::from_fn::<u8, 32, _>(|index| index as u8 + 1));
+        let error = SwarmKey::decode(b"/key/swarm/psk/1.0.0/\n/base64/\n!!!!\n").err();
+        assert!(matches!(error.unwrap().downcast_ref(), Some(PnetError::InvalidBase64())));
+
+        let binary = [&b"/key/swarm/psk/1.0.0/\n/bin/\n"[..], &[9u8; 32]].concat();
+        assert_eq!(SwarmKey::decode(&binary).unwrap().0, [9u8; 32]);
+
+        assert!(SwarmKey::decode(b"/key/swarm/psk/2.0.0/\n/base16/\n00").is_err());
+        assert!(SwarmKey::decode(b"/key/swarm/psk/1.0.0/\n/base64/\nAAAA").is_err());
+        assert!(SwarmKey::decode(b"/key/swarm/psk/1.0.0/\n/base16/\n0102").is_err());
+    }
+
+    #[test]
+    fn test_decode_invalid_base16() {
+        let signed = format!("+1{}", "00".repeat(31));
+        let multibyte = format!("0{}é{}", "0".repeat(31), "0".repeat(30));
+        for hex in [signed, multibyte] {
+            assert_eq!(hex.len(), 64);
+            let error = SwarmKey::decode(format!("/key/swarm/psk/1.0.0/\n/base16/\n{hex}\n").as_bytes()).err();
+            assert!(matches!(error.unwrap().downcast_ref(), Some(PnetError::InvalidBase16())));
+        }
+    }
+}
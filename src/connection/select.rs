@@ -0,0 +1,416 @@
+//! A multistream-select 1.0.0 engine that negotiates protocols over any [MessageStream] or [AsyncMessageStream].
+//!
+//! See the multistream-select spec [here](https://github.com/multiformats/multistream-select).
+use std::{error::Error, future::Future};
+
+use unsigned_varint::{decode, encode};
+
+use super::multistream::MultistreamError;
+use crate::auth::SecureChannel;
+
+pub const MULTISTREAM_PROTOCOL: &str = "/multistream/1.0.0";
+const NOT_AVAILABLE: &str = "na";
+const LIST: &str = "ls";
+/// The longest multistream-select message, including its trailing newline, that is accepted from a peer.
+pub const MAX_MESSAGE_LEN: usize = 64 * 1024;
+/// The number of bytes in the varint length prefix of a [MAX_MESSAGE_LEN] message.
+pub const MAX_LENGTH_PREFIX: usize = 3;
+
+/// The multistream-select variants a dialer can negotiate with, listeners handle both alike.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Version {
+    /// Every proposal waits for the listener's answer before anything else is sent.
+    #[default]
+    V1,
+    /// When a single protocol is proposed the dialer assumes it is accepted, the header and proposal
+    /// are sent with the first application data and the answer is checked before the first read.
+    V1Lazy,
+}
+
+/// A transport that multistream-select messages can be exchanged over. Messages are passed
+/// without their varint length prefix and trailing newline, which are added by the implementor.
+pub trait MessageStream {
+    /// Reads the next multistream-select message.
+    fn read_message(&mut self) -> Result<Vec<u8>, Box<dyn Error>>;
+
+    /// Writes a single multistream-select message.
+    fn write_message(&mut self, message: &[u8]) -> Result<(), Box<dyn Error>>;
+}
+
+/// Encodes a message as its varint length, the message and a trailing newline.
+pub fn encode_message(message: &[u8]) -> Vec<u8> {
+    let mut buf = encode::usize_buffer();
+    let encoded_size = encode::usize(message.len() + 1, &mut buf);
+    [encoded_size, message, b"\n"].concat()
+}
+
+/// A decoded message without its newline, and the number of bytes it took up including the length prefix.
+pub type DecodedMessage = (Vec<u8>, usize);
+
+/// Decodes the message at the start of `buffer`, or returns [None] if the message has not fully arrived.
+///
+/// Messages longer than [MAX_MESSAGE_LEN] are rejected as soon as their length prefix is known.
+pub fn decode_message(buffer: &[u8]) -> Result<Option<DecodedMessage>, Box<dyn Error>> {
+    let (len, rest) = match decode::usize(buffer) {
+        Ok(decoded) => decoded,
+        Err(decode::Error::Insufficient) if buffer.len() < MAX_LENGTH_PREFIX => return Ok(None),
+        Err(decode::Error::Insufficient) => return Err(MultistreamError::MessageTooLong().into()),
+        Err(error) => return Err(error.into()),
+    };
+    if len > MAX_MESSAGE_LEN {
+        return Err(MultistreamError::MessageTooLong().into());
+    }
+    if rest.len() < len {
+        return Ok(None);
+    }
+    match rest[..len].split_last() {
+        Some((b'\n', message)) => Ok(Some((message.to_vec(), buffer.len() - rest.len() + len))),
+        _ => Err(MultistreamError::MissingNewline().into()),
+    }
+}
+
+/// Runs multistream-select inside a [SecureChannel], such as a noise channel or a muxer substream.
+///
+/// A channel message may hold several multistream messages, or the start of the data that follows
+/// negotiation, so anything read past the last message is kept and can be taken with [ChannelMessages::into_buffer].
+pub struct ChannelMessages<'c> {
+    channel: &'c mut dyn SecureChannel,
+    buffer: Vec<u8>,
+}
+
+impl<'c> ChannelMessages<'c> {
+    pub fn new(channel: &'c mut dyn SecureChannel) -> Self {
+        ChannelMessages { channel, buffer: Vec::new() }
+    }
+
+    /// The bytes read from the channel after the last negotiated message.
+    pub fn into_buffer(self) -> Vec<u8> {
+        self.buffer
+    }
+}
+
+impl<'c> MessageStream for ChannelMessages<'c> {
+    fn read_message(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+        loop {
+            if let Some((message, len)) = decode_message(&self.buffer)? {
+                self.buffer.drain(..len);
+                return Ok(message);
+            }
+            let mut data = self.channel.read()?;
+            self.buffer.append(&mut data);
+        }
+    }
+
+    fn write_message(&mut self, message: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.channel.write(&encode_message(message))
+    }
+}
+
+/// The async counterpart of [MessageStream], used to negotiate over non-blocking transports.
+pub trait AsyncMessageStream: Send {
+    /// Reads the next multistream-select message.
+    fn read_message(&mut self) -> impl Future<Output = Result<Vec<u8>, Box<dyn Error>>> + Send;
+
+    /// Writes a single multistream-select message.
+    fn write_message(&mut self, message: &[u8]) -> impl Future<Output = Result<(), Box<dyn Error>>> + Send;
+}
+
+/// The side a [Negotiator] takes once the `/multistream/1.0.0` headers have been exchanged.
+#[derive(Clone, Copy)]
+enum Role<'p> {
+    Dialer(&'p [&'p str]),
+    Lazy(&'p str),
+    Listener(&'p [&'p str]),
+    Lister,
+}
+
+/// The message a [Negotiator] waits for next.
+#[derive(Clone, Copy)]
+enum State<'p> {
+    /// The remote header, after which the negotiation continues as `Role`
+    Header(Role<'p>),
+    /// The listener's answer to the proposal of `protocols[index]`
+    Answer(&'p [&'p str], usize),
+    /// The listener's answer to a [Version::V1Lazy] proposal
+    Confirmation(&'p str),
+    /// The dialer's next proposal or `ls`
+    Proposal(&'p [&'p str]),
+    /// The listener's answer to `ls`
+    Listing,
+    Done,
+}
+
+/// How a negotiation ended.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// Both peers agreed on this protocol
+    Protocol(String),
+    /// The protocols the listener answered `ls` with
+    Listing(Vec<String>),
+}
+
+impl Outcome {
+    fn into_protocol(self) -> String {
+        match self {
+            Outcome::Protocol(protocol) => protocol,
+            Outcome::Listing(_) => unreachable!("only a lister ends with a listing"),
+        }
+    }
+}
+
+/// What a [Negotiator] made of a received message, the messages in `send` go out before anything else is read.
+#[derive(Debug, Default)]
+pub struct Progress {
+    pub send: Vec<Vec<u8>>,
+    /// Set once the negotiation is over
+    pub outcome: Option<Outcome>,
+}
+
+/// The multistream-select state machine shared by every engine in this module, it decides what to send and
+/// how to answer each message without doing any I/O, so blocking and async streams negotiate the same way.
+///
+/// A stream runs it with [Negotiator::start] followed by [Negotiator::receive] for every message until an
+/// [Outcome] is reached, see [negotiate] and [negotiate_async].
+pub struct Negotiator<'p> {
+    state: State<'p>,
+}
+
+impl<'p> Negotiator<'p> {
+    /// Dialer side, see [dial].
+    pub fn dial(protocols: &'p [&'p str]) -> Self {
+        Negotiator { state: State::Header(Role::Dialer(protocols)) }
+    }
+
+    /// Dialer side after the headers have been exchanged, see [propose].
+    pub fn propose(protocols: &'p [&'p str]) -> Self {
+        Negotiator { state: State::Answer(protocols, 0) }
+    }
+
+    /// Dialer side of a [Version::V1Lazy] negotiation whose messages were already sent, see [confirm].
+    pub fn confirm(protocol: &'p str) -> Self {
+        Negotiator { state: State::Header(Role::Lazy(protocol)) }
+    }
+
+    /// Listener side, see [listen].
+    pub fn listen(protocols: &'p [&'p str]) -> Self {
+        Negotiator { state: State::Header(Role::Listener(protocols)) }
+    }
+
+    /// Dialer side asking for the listener's protocols, see [list].
+    pub fn list() -> Self {
+        Negotiator { state: State::Header(Role::Lister) }
+    }
+
+    /// The messages to send before the first one is received.
+    pub fn start(&self) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+        match self.state {
+            State::Header(Role::Dialer(_) | Role::Lister) => Ok(vec![MULTISTREAM_PROTOCOL.as_bytes().to_vec()]),
+            State::Answer(protocols, index) => Ok(vec![proposal(protocols, index)?]),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// Handles the next message received from the peer.
+    pub fn receive(&mut self, message: &[u8]) -> Result<Progress, Box<dyn Error>> {
+        let (state, progress) = match self.state {
+            State::Header(role) => {
+                if message != MULTISTREAM_PROTOCOL.as_bytes() {
+                    return Err(MultistreamError::Negotiation().into());
+                }
+                match role {
+                    Role::Dialer(protocols) => (State::Answer(protocols, 0), Progress::send(proposal(protocols, 0)?)),
+                    Role::Lazy(protocol) => (State::Confirmation(protocol), Progress::default()),
+                    Role::Listener(protocols) => {
+                        (State::Proposal(protocols), Progress::send(MULTISTREAM_PROTOCOL.as_bytes().to_vec()))
+                    }
+                    Role::Lister => (State::Listing, Progress::send(LIST.as_bytes().to_vec())),
+                }
+            }
+            State::Answer(protocols, index) => {
+                if message == protocols[index].as_bytes() {
+                    (State::Done, Progress::done(Outcome::Protocol(protocols[index].to_owned())))
+                } else if message == NOT_AVAILABLE.as_bytes() {
+                    (State::Answer(protocols, index + 1), Progress::send(proposal(protocols, index + 1)?))
+                } else {
+                    return Err(unexpected(message));
+                }
+            }
+            State::Confirmation(protocol) => {
+                if message == protocol.as_bytes() {
+                    (State::Done, Progress::done(Outcome::Protocol(protocol.to_owned())))
+                } else if message == NOT_AVAILABLE.as_bytes() {
+                    // A late `na` means the data already sent was not understood
+                    return Err(MultistreamError::Rejected(protocol.to_owned()).into());
+                } else {
+                    return Err(unexpected(message));
+                }
+            }
+            State::Proposal(protocols) => {
+                if message == LIST.as_bytes() {
+                    let listing = protocols
+                        .iter()
+                        .flat_map(|protocol| encode_message(protocol.as_bytes()))
+                        .collect();
+                    (State::Proposal(protocols), Progress::send(listing))
+                } else {
+                    match protocols.iter().find(|protocol| protocol.as_bytes() == message) {
+                        Some(protocol) => (
+                            State::Done,
+                            Progress {
+                                send: vec![protocol.as_bytes().to_vec()],
+                                outcome: Some(Outcome::Protocol(protocol.to_string())),
+                            },
+                        ),
+                        None => (State::Proposal(protocols), Progress::send(NOT_AVAILABLE.as_bytes().to_vec())),
+                    }
+                }
+            }
+            State::Listing => {
+                let mut listing = message;
+                let mut protocols = Vec::new();
+                while !listing.is_empty() {
+                    let (protocol, len) = decode_message(listing)?.ok_or(MultistreamError::Negotiation())?;
+                    protocols.push(String::from_utf8(protocol)?);
+                    listing = &listing[len..];
+                }
+                (State::Done, Progress::done(Outcome::Listing(protocols)))
+            }
+            State::Done => return Err(unexpected(message)),
+        };
+        self.state = state;
+        Ok(progress)
+    }
+}
+
+impl Progress {
+    fn send(message: Vec<u8>) -> Self {
+        Progress { send: vec![message], outcome: None }
+    }
+
+    fn done(outcome: Outcome) -> Self {
+        Progress { send: Vec::new(), outcome: Some(outcome) }
+    }
+}
+
+/// The proposal of `protocols[index]`, once every protocol has been refused the negotiation fails.
+fn proposal(protocols: &[&str], index: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    match protocols.get(index) {
+        Some(protocol) => Ok(protocol.as_bytes().to_vec()),
+        None => Err(MultistreamError::Unsupported().into()),
+    }
+}
+
+fn unexpected(message: &[u8]) -> Box<dyn Error> {
+    MultistreamError::UnexpectedMessage(String::from_utf8_lossy(message).into()).into()
+}
+
+/// Runs `negotiator` over a blocking [MessageStream] until it reaches an [Outcome].
+pub fn negotiate<M: MessageStream + ?Sized>(
+    stream: &mut M,
+    mut negotiator: Negotiator,
+) -> Result<Outcome, Box<dyn Error>> {
+    for message in negotiator.start()? {
+        stream.write_message(&message)?;
+    }
+    loop {
+        let progress = negotiator.receive(&stream.read_message()?)?;
+        for message in progress.send {
+            stream.write_message(&message)?;
+        }
+        if let Some(outcome) = progress.outcome {
+            return Ok(outcome);
+        }
+    }
+}
+
+/// Runs `negotiator` over an [AsyncMessageStream] until it reaches an [Outcome].
+pub async fn negotiate_async<M: AsyncMessageStream + ?Sized>(
+    stream: &mut M,
+    mut negotiator: Negotiator<'_>,
+) -> Result<Outcome, Box<dyn Error>> {
+    let start = negotiator.start()?;
+    for message in start {
+        stream.write_message(&message).await?;
+    }
+    loop {
+        let message = stream.read_message().await?;
+        let progress = negotiator.receive(&message)?;
+        for message in progress.send {
+            stream.write_message(&message).await?;
+        }
+        if let Some(outcome) = progress.outcome {
+            return Ok(outcome);
+        }
+    }
+}
+
+/// Negotiates a protocol from the dialer side, `protocols` are proposed in order of preference
+/// and the first one the listener echoes back is returned, every `na` moves on to the next.
+pub fn dial<M: MessageStream + ?Sized>(stream: &mut M, protocols: &[&str]) -> Result<String, Box<dyn Error>> {
+    Ok(negotiate(stream, Negotiator::dial(protocols))?.into_protocol())
+}
+
+/// Proposes `protocols` on a stream whose `/multistream/1.0.0` header has already been exchanged,
+/// such as one that has been used for [list].
+pub fn propose<M: MessageStream + ?Sized>(stream: &mut M, protocols: &[&str]) -> Result<String, Box<dyn Error>> {
+    Ok(negotiate(stream, Negotiator::propose(protocols))?.into_protocol())
+}
+
+/// The header and a single proposal as written by a [Version::V1Lazy] dialer, to be sent ahead of its first data.
+pub fn encode_lazy(protocol: &str) -> Vec<u8> {
+    [encode_message(MULTISTREAM_PROTOCOL.as_bytes()), encode_message(protocol.as_bytes())].concat()
+}
+
+/// Reads the listener's answer to a [Version::V1Lazy] proposal, its header followed by either the
+/// protocol or `na`. A late `na` means the data already sent was not understood and the stream must not be used.
+pub fn confirm<M: MessageStream + ?Sized>(stream: &mut M, protocol: &str) -> Result<(), Box<dyn Error>> {
+    negotiate(stream, Negotiator::confirm(protocol))?;
+    Ok(())
+}
+
+/// Negotiates a protocol from the listener side, answering `ls` with `protocols` and every proposal
+/// that is not in `protocols` with `na` until the dialer proposes one that is.
+pub fn listen<M: MessageStream + ?Sized>(stream: &mut M, protocols: &[&str]) -> Result<String, Box<dyn Error>> {
+    Ok(negotiate(stream, Negotiator::listen(protocols))?.into_protocol())
+}
+
+/// Asks the listener for the protocols it supports with the `ls` command, the stream is left
+/// mid-negotiation so a protocol can be chosen with [propose] afterwards.
+pub fn list<M: MessageStream + ?Sized>(stream: &mut M) -> Result<Vec<String>, Box<dyn Error>> {
+    match negotiate(stream, Negotiator::list())? {
+        Outcome::Listing(protocols) => Ok(protocols),
+        Outcome::Protocol(_) => unreachable!("a lister always ends with a listing"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::{dial, list, listen, propose, ChannelMessages};
+    use crate::{auth::SecureChannel, muxer::memory};
+
+    #[test]
+    fn test_select() {
+        let (mut local, mut remote) = memory::pair();
+        let listener = thread::spawn(move || {
+            let mut messages = ChannelMessages::new(&mut remote);
+            assert_eq!(listen(&mut messages, &["/echo/1.0.0", "/ipfs/id/1.0.0"]).unwrap(), "/ipfs/id/1.0.0");
+            assert_eq!(listen(&mut messages, &["/echo/1.0.0"]).unwrap(), "/echo/1.0.0");
+            assert!(messages.into_buffer().is_empty());
+            remote.write(b"after negotiation").unwrap();
+        });
+
+        let mut messages = ChannelMessages::new(&mut local);
+        assert_eq!(list(&mut messages).unwrap(), ["/echo/1.0.0", "/ipfs/id/1.0.0"]);
+        assert_eq!(propose(&mut messages, &["/unknown/1.0.0", "/ipfs/id/1.0.0"]).unwrap(), "/ipfs/id/1.0.0");
+        // Every proposal is refused, the listener keeps waiting for another one
+        assert!(dial(&mut messages, &["/unknown/1.0.0"]).is_err());
+        assert_eq!(propose(&mut messages, &["/echo/1.0.0"]).unwrap(), "/echo/1.0.0");
+        assert!(messages.into_buffer().is_empty());
+
+        // Data sent after negotiation is left on the channel
+        assert_eq!(local.read().unwrap(), b"after negotiation");
+        listener.join().unwrap();
+    }
+}
@@ -0,0 +1,234 @@
+//! Identity keys that peers sign their noise static key with during the handshake.
+//!
+//! Keys are exchanged as a protobuf encoded [handshake::PublicKey], see the key encodings
+//! [here](https://github.com/libp2p/specs/blob/master/peer-ids/peer-ids.md#keys).
+use k256::ecdsa::signature::{Signer as _, Verifier as _};
+use p256::pkcs8::{DecodePublicKey, EncodePublicKey};
+use rand::{rngs::OsRng, RngCore};
+use rsa::{pkcs1v15, sha2::Sha256, traits::PublicKeyParts};
+use thiserror::Error;
+
+use crate::{handshake, peer_id::PeerId};
+
+/// The smallest RSA modulus accepted, libp2p requires at least 2048 bits.
+const MIN_RSA_BITS: usize = 2048;
+
+#[derive(Error, Debug)]
+pub enum IdentityError {
+    #[error("identity keys of type {0} are not supported")]
+    UnsupportedKeyType(i32),
+    #[error("the identity key is not a valid key of its type")]
+    InvalidPublicKey(),
+    #[error("the signature is malformed or was not made by the identity key")]
+    InvalidSignature(),
+}
+
+/// The keypair we authenticate as, its [PublicKey] determines our [PeerId].
+///
+/// RSA and ECDSA identities can only be verified, we do not authenticate with them ourselves.
+pub enum Keypair {
+    Ed25519(ed25519_dalek::Keypair),
+    /// Signs with ECDSA over SHA-256, signatures are DER encoded.
+    Secp256k1(k256::ecdsa::SigningKey),
+}
+
+impl Keypair {
+    pub fn generate_ed25519() -> Self {
+        Keypair::Ed25519(ed25519_dalek::Keypair::generate(&mut OsRng))
+    }
+
+    pub fn generate_secp256k1() -> Self {
+        loop {
+            // Roughly 1 in 2^128 byte strings is not a valid scalar, such as zero
+            let mut secret = [0u8; 32];
+            OsRng.fill_bytes(&mut secret);
+            if let Ok(key) = k256::ecdsa::SigningKey::from_slice(&secret) {
+                return Keypair::Secp256k1(key);
+            }
+        }
+    }
+
+    pub fn public(&self) -> PublicKey {
+        match self {
+            Keypair::Ed25519(keypair) => PublicKey::Ed25519(keypair.public),
+            Keypair::Secp256k1(key) => PublicKey::Secp256k1(*key.verifying_key()),
+        }
+    }
+
+    /// Signs `message` in the format libp2p expects for the key type.
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        match self {
+            Keypair::Ed25519(keypair) => ed25519_dalek::Signer::sign(keypair, message).to_bytes().to_vec(),
+            Keypair::Secp256k1(key) => {
+                let signature: k256::ecdsa::Signature = key.sign(message);
+                signature.to_der().as_bytes().to_vec()
+            }
+        }
+    }
+}
+
+impl Clone for Keypair {
+    fn clone(&self) -> Self {
+        match self {
+            Keypair::Ed25519(keypair) => Keypair::Ed25519(
+                ed25519_dalek::Keypair::from_bytes(&keypair.to_bytes()).expect("a keypair's own bytes are valid"),
+            ),
+            Keypair::Secp256k1(key) => Keypair::Secp256k1(key.clone()),
+        }
+    }
+}
+
+impl From<ed25519_dalek::Keypair> for Keypair {
+    fn from(keypair: ed25519_dalek::Keypair) -> Self {
+        Keypair::Ed25519(keypair)
+    }
+}
+
+/// The public half of an identity key, used to verify the signature over a remote noise static key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PublicKey {
+    Ed25519(ed25519_dalek::PublicKey),
+    /// Compressed SEC1 encoded on the wire.
+    Secp256k1(k256::ecdsa::VerifyingKey),
+    /// PKIX DER encoded on the wire with a modulus of at least 2048 bits, signatures are PKCS#1 v1.5 over SHA-256.
+    Rsa(rsa::RsaPublicKey),
+    /// A P-256 key, PKIX DER encoded on the wire with DER encoded signatures over SHA-256.
+    Ecdsa(p256::ecdsa::VerifyingKey),
+}
+
+impl PublicKey {
+    /// Decodes the key inside a [handshake::PublicKey].
+    pub fn from_protobuf(key: &handshake::PublicKey) -> Result<Self, IdentityError> {
+        match handshake::KeyType::from_i32(key.r#type) {
+            Some(handshake::KeyType::Ed25519) => ed25519_dalek::PublicKey::from_bytes(&key.data)
+                .map(PublicKey::Ed25519)
+                .map_err(|_| IdentityError::InvalidPublicKey()),
+            Some(handshake::KeyType::Secp256k1) => k256::ecdsa::VerifyingKey::from_sec1_bytes(&key.data)
+                .map(PublicKey::Secp256k1)
+                .map_err(|_| IdentityError::InvalidPublicKey()),
+            Some(handshake::KeyType::Rsa) => rsa::RsaPublicKey::from_public_key_der(&key.data)
+                .ok()
+                .filter(|key| key.n().bits() >= MIN_RSA_BITS)
+                .map(PublicKey::Rsa)
+                .ok_or(IdentityError::InvalidPublicKey()),
+            Some(handshake::KeyType::Ecdsa) => p256::ecdsa::VerifyingKey::from_public_key_der(&key.data)
+                .map(PublicKey::Ecdsa)
+                .map_err(|_| IdentityError::InvalidPublicKey()),
+            None => Err(IdentityError::UnsupportedKeyType(key.r#type)),
+        }
+    }
+
+    pub fn to_protobuf(&self) -> handshake::PublicKey {
+        let (key_type, data) = match self {
+            PublicKey::Ed25519(key) => (handshake::KeyType::Ed25519, key.as_bytes().to_vec()),
+            PublicKey::Secp256k1(key) => {
+                (handshake::KeyType::Secp256k1, key.to_encoded_point(true).as_bytes().to_vec())
+            }
+            PublicKey::Rsa(key) => (handshake::KeyType::Rsa, pkix_der(key)),
+            PublicKey::Ecdsa(key) => (handshake::KeyType::Ecdsa, pkix_der(key)),
+        };
+        handshake::PublicKey { r#type: key_type as i32, data }
+    }
+
+    /// Verifies a signature over `message`, secp256k1 signatures may be DER encoded or in the 64 byte compact form.
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), IdentityError> {
+        let valid = match self {
+            PublicKey::Ed25519(key) => ed25519_dalek::Signature::from_bytes(signature)
+                .is_ok_and(|signature| ed25519_dalek::Verifier::verify(key, message, &signature).is_ok()),
+            PublicKey::Secp256k1(key) => k256::ecdsa::Signature::from_der(signature)
+                .or_else(|_| k256::ecdsa::Signature::from_slice(signature))
+                // Signers are not required to produce low-S signatures, which is all k256 accepts
+                .map(|signature| signature.normalize_s().unwrap_or(signature))
+                .is_ok_and(|signature| key.verify(message, &signature).is_ok()),
+            PublicKey::Rsa(key) => pkcs1v15::Signature::try_from(signature).is_ok_and(|signature| {
+                pkcs1v15::VerifyingKey::<Sha256>::new(key.clone()).verify(message, &signature).is_ok()
+            }),
+            PublicKey::Ecdsa(key) => p256::ecdsa::Signature::from_der(signature)
+                .is_ok_and(|signature| key.verify(message, &signature).is_ok()),
+        };
+        if !valid {
+            return Err(IdentityError::InvalidSignature());
+        }
+        Ok(())
+    }
+
+    pub fn to_peer_id(&self) -> PeerId {
+        PeerId::from_public_key(&self.to_protobuf())
+    }
+}
+
+fn pkix_der<K: EncodePublicKey>(key: &K) -> Vec<u8> {
+    key.to_public_key_der().expect("public keys always encode").into_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+
+    use super::{Keypair, PublicKey};
+    use crate::{handshake, peer_id::PeerId};
+
+    fn hex(data: &str) -> Vec<u8> {
+        (0..data.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&data[index..index + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_signatures() {
+        for keypair in [Keypair::generate_ed25519(), Keypair::generate_secp256k1()] {
+            let public = PublicKey::from_protobuf(&keypair.public().to_protobuf()).unwrap();
+            assert_eq!(public, keypair.public());
+            let signature = keypair.sign(b"noise-libp2p-static-key:");
+            assert!(public.verify(b"noise-libp2p-static-key:", &signature).is_ok());
+            assert!(public.verify(b"another message", &signature).is_err());
+        }
+
+        // Secp256k1 peer ids inline the encoded 33 byte compressed key with the identity multihash
+        let keypair = Keypair::generate_secp256k1();
+        let encoded = keypair.public().to_protobuf().encode_to_vec();
+        let peer_id: PeerId = keypair.public().to_peer_id().to_string().parse().unwrap();
+        assert_eq!(peer_id.as_bytes(), [&[0x00, encoded.len() as u8][..], &encoded].concat());
+        assert_eq!(peer_id.public_key(), Some(keypair.public().to_protobuf()));
+        assert!(peer_id.to_string().starts_with("16Uiu2HA"));
+
+        // Compact signatures are accepted alongside DER
+        let Keypair::Secp256k1(key) = &keypair else { unreachable!() };
+        let signature: k256::ecdsa::Signature = k256::ecdsa::signature::Signer::sign(key, b"compact");
+        assert!(keypair.public().verify(b"compact", &signature.to_bytes()).is_ok());
+    }
+
+    #[test]
+    fn test_verify_only_keys() {
+        // Produced with Python's cryptography (RSA) and OpenSSL (ECDSA) over a signed noise static key of 0x00..0x1f
+        let message = [&b"noise-libp2p-static-key:"[..], &(0..32).collect::<Vec<u8>>()].concat();
+        let rsa = handshake::PublicKey {
+            r#type: handshake::KeyType::Rsa as i32,
+            data: hex("30820122300d06092a864886f70d01010105000382010f003082010a0282010100cbcc91cf0d4bd081f6f951d1b4b68c12c29b674f40c57e545a2b1f1769a6f8a25f08c0ac69cffc52019a9065591a6dedefa3b9fc2158a8e0d5a70615b5f1e14b6f72a3c328aae70c3606efad138e2cf96cf7427b66bcf394c9fc4143139731e5111a23ecd0b0bef32fce70a255f89273461e62fdbe3e402765412f4251ed503f980cb8adb3ee3b59f1dc8741ef856c87c13b36a0f2438b82a97e58f3d6cefb8440909096a5cc91db1bae9d556b06b38983cd307fb1a905c19324c974eea46e01af73b767d29bc70f0b17aad62729e015875bd72348b2c69f1380f514e90aa64eb57c3a253d491c3499900d54c490192ef1ac2697e8cc1d4785fb5699f611c3770203010001"),
+        };
+        let rsa_signature = hex("118c2474ccbb715a643c5e224279667c959d8467f92124a5e0e0a988245a420877a66bd456b85b629228986a67fb4da309fac893ead7e01c40aa59218aff529df0be907157394f96f15b86c9d05c21d8c2f4a456993a9c4f7a07336200dbf8e14fd43913eb0e01b890b5f96d2c1905a2a7564c696ace42a85877091b173027a1aa186502acfdd5866ad78ce8aa4e41702bc14918a50f4cab00ba4e8b6bf0f60ca724d543d17c29e2b29c618382510ed3c8971ceee48a78b6decc8d85477472f95ea1854a78cb2c044e94eabd46ffc4e12b3f9968c1a8e89470792c79891ecb31286f20d26ae7051d9c4cd3d15425a5b6ef396e4f00156f62a027472abadd07b1");
+        let ecdsa = handshake::PublicKey {
+            r#type: handshake::KeyType::Ecdsa as i32,
+            data: hex("3059301306072a8648ce3d020106082a8648ce3d030107034200044b096946e81cc6c3ae9658ec06ac540f7a5cb6531b6033aa15951dd84cee6e628521a8d0854d991c1350188b9f41db547150a782b381fccb7b3b47550f0d1ddc"),
+        };
+        let ecdsa_signature = hex("3046022100a2956a2f3b3582e2252b80be8b992e1b7c21facc4ba8305196a94be89aa7678b0221008170134c5e62892a719621cbb5f5a6bca5069bee5fcbff21fc90afb13cc7951b");
+
+        for (key, signature) in [(rsa, rsa_signature), (ecdsa, ecdsa_signature)] {
+            let public = PublicKey::from_protobuf(&key).unwrap();
+            assert_eq!(public.to_protobuf(), key);
+            assert!(public.verify(&message, &signature).is_ok());
+            assert!(public.verify(b"another message", &signature).is_err());
+        }
+
+        // RSA moduli under 2048 bits are refused
+        let weak_rsa = handshake::PublicKey {
+            r#type: handshake::KeyType::Rsa as i32,
+            data: hex("30819f300d06092a864886f70d010101050003818d0030818902818100bb8ac1f5d496c077cda876f5be778494b7adfa7d4610854bd4ba87bac20216bf0389db8ed02f0d6ac48744de6c5ffa1c00c61e11aadf28a799c78a7bffcc725a5689f372b1ecd5d375ef9f0459d96a0aa49acecf936aa76b6fbbaa1dad180cf8f0ac5867187a54e930c295282a1c1868e8f04b93c3bf2127b6c5fcf384ee88d90203010001"),
+        };
+        assert!(PublicKey::from_protobuf(&weak_rsa).is_err());
+
+        let unknown = handshake::PublicKey { r#type: 7, data: vec![] };
+        assert!(PublicKey::from_protobuf(&unknown).is_err());
+    }
+}
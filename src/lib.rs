@@ -1,6 +1,9 @@
 #![feature(trait_alias)]
 pub mod auth;
 pub mod connection;
+pub mod identity;
+pub mod muxer;
+pub mod peer_id;
 pub mod handshake {
     include!(concat!(env!("OUT_DIR"), "/handshake.rs"));
 }
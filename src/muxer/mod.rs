@@ -0,0 +1,177 @@
+//! Stream multiplexers that run many independent substreams over a single [crate::auth::SecureChannel].
+use std::error::Error;
+
+use crate::{
+    auth::SecureChannel,
+    connection::select::{self, ChannelMessages},
+};
+
+use self::{mplex::Mplex, yamux::Yamux};
+
+pub mod mplex;
+pub mod yamux;
+
+/// The stream multiplexers that can be negotiated once a connection has been secured.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MuxerProtocol {
+    Yamux,
+    Mplex,
+}
+
+impl MuxerProtocol {
+    pub fn name(&self) -> &[u8] {
+        match *self {
+            MuxerProtocol::Yamux => b"/yamux/1.0.0\n",
+            MuxerProtocol::Mplex => b"/mplex/6.7.0\n",
+        }
+    }
+
+    /// The protocol id negotiated with multistream-select, [MuxerProtocol::name] without the trailing newline.
+    pub fn protocol(&self) -> &str {
+        match *self {
+            MuxerProtocol::Yamux => "/yamux/1.0.0",
+            MuxerProtocol::Mplex => "/mplex/6.7.0",
+        }
+    }
+}
+
+/// A negotiated muxer session, its substreams are handed out as boxed [SecureChannel]s.
+pub enum Muxer<'a> {
+    Yamux(Yamux<'a>),
+    Mplex(Mplex<'a>),
+}
+
+impl<'a> Muxer<'a> {
+    /// Negotiates one of `muxers` over a secure channel with multistream-select and starts a session with it.
+    ///
+    /// The `initiator` proposes `muxers` in order of preference, the responder accepts the first proposal it also supports.
+    /// When a muxer in `muxers` was already agreed during the handshake, see [SecureChannel::muxer], it is used
+    /// straight away without another round of multistream-select.
+    pub fn negotiate(
+        mut channel: Box<dyn SecureChannel + 'a>,
+        muxers: &[MuxerProtocol],
+        initiator: bool,
+    ) -> Result<Self, Box<dyn Error>> {
+        if let Some(muxer) = channel.muxer().filter(|muxer| muxers.contains(muxer)) {
+            return Ok(Self::new(channel, muxer, initiator));
+        }
+        let protocols: Vec<&str> = muxers.iter().map(MuxerProtocol::protocol).collect();
+        let mut messages = ChannelMessages::new(channel.as_mut());
+        let protocol = if initiator {
+            select::dial(&mut messages, &protocols)?
+        } else {
+            select::listen(&mut messages, &protocols)?
+        };
+        // The remote peer may have sent its first muxer frames along with the last negotiation message
+        let buffer = messages.into_buffer();
+        let protocol = *muxers
+            .iter()
+            .find(|muxer| muxer.protocol() == protocol)
+            .expect("the negotiated protocol is one of the muxers");
+        Ok(Self::with_buffer(channel, protocol, initiator, buffer))
+    }
+
+    /// Starts a session with a muxer that both peers have already agreed on.
+    pub fn new(channel: Box<dyn SecureChannel + 'a>, protocol: MuxerProtocol, initiator: bool) -> Self {
+        Self::with_buffer(channel, protocol, initiator, vec![])
+    }
+
+    fn with_buffer(
+        channel: Box<dyn SecureChannel + 'a>,
+        protocol: MuxerProtocol,
+        initiator: bool,
+        buffer: Vec<u8>,
+    ) -> Self {
+        match protocol {
+            MuxerProtocol::Yamux => Muxer::Yamux(Yamux::new(channel, initiator).with_buffer(buffer)),
+            MuxerProtocol::Mplex => Muxer::Mplex(Mplex::new(channel).with_buffer(buffer)),
+        }
+    }
+
+    /// The muxer that was negotiated.
+    pub fn protocol(&self) -> MuxerProtocol {
+        match self {
+            Muxer::Yamux(_) => MuxerProtocol::Yamux,
+            Muxer::Mplex(_) => MuxerProtocol::Mplex,
+        }
+    }
+
+    /// Opens a new outbound substream.
+    pub fn open_stream(&self) -> Result<Box<dyn SecureChannel + 'a>, Box<dyn Error>> {
+        Ok(match self {
+            Muxer::Yamux(yamux) => Box::new(yamux.open_stream()?),
+            Muxer::Mplex(mplex) => Box::new(mplex.open_stream()?),
+        })
+    }
+
+    /// Blocks until the remote peer opens a substream.
+    pub fn accept_stream(&self) -> Result<Box<dyn SecureChannel + 'a>, Box<dyn Error>> {
+        Ok(match self {
+            Muxer::Yamux(yamux) => Box::new(yamux.accept_stream()?),
+            Muxer::Mplex(mplex) => Box::new(mplex.accept_stream()?),
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod memory {
+    use std::{
+        error::Error,
+        sync::mpsc::{channel, Receiver, Sender},
+    };
+
+    use crate::auth::SecureChannel;
+
+    /// An in-memory [SecureChannel] used to exercise muxers without a network peer.
+    pub struct MemoryChannel {
+        sender: Sender<Vec<u8>>,
+        receiver: Receiver<Vec<u8>>,
+    }
+
+    /// Creates both ends of a connected pair of [MemoryChannel]s.
+    pub fn pair() -> (MemoryChannel, MemoryChannel) {
+        let (local_sender, remote_receiver) = channel();
+        let (remote_sender, local_receiver) = channel();
+        (
+            MemoryChannel { sender: local_sender, receiver: local_receiver },
+            MemoryChannel { sender: remote_sender, receiver: remote_receiver },
+        )
+    }
+
+    impl SecureChannel for MemoryChannel {
+        fn write(&mut self, data: &[u8]) -> Result<(), Box<dyn Error>> {
+            Ok(self.sender.send(data.to_vec())?)
+        }
+
+        fn read(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+            Ok(self.receiver.recv()?)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::{memory, Muxer, MuxerProtocol};
+
+    #[test]
+    fn test_negotiate() {
+        let (local, remote) = memory::pair();
+        let responder = thread::spawn(move || {
+            let muxer = Muxer::negotiate(Box::new(remote), &[MuxerProtocol::Mplex], false).unwrap();
+            assert_eq!(muxer.protocol(), MuxerProtocol::Mplex);
+            let mut stream = muxer.accept_stream().unwrap();
+            let received = stream.read().unwrap();
+            stream.write(&received).unwrap();
+        });
+
+        // Yamux is proposed first and refused
+        let muxer = Muxer::negotiate(Box::new(local), &[MuxerProtocol::Yamux, MuxerProtocol::Mplex], true).unwrap();
+        assert_eq!(muxer.protocol(), MuxerProtocol::Mplex);
+        let mut stream = muxer.open_stream().unwrap();
+        stream.write(b"echo").unwrap();
+        assert_eq!(stream.read().unwrap(), b"echo");
+        responder.join().unwrap();
+    }
+}
@@ -0,0 +1,457 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    error::Error,
+    rc::Rc,
+};
+
+use thiserror::Error;
+use unsigned_varint::{decode, encode};
+
+use crate::auth::SecureChannel;
+
+/// Mplex implementations must reject messages larger than 1MiB.
+const MAX_MESSAGE_LEN: usize = 1024 * 1024;
+
+/// Outgoing messages are split so that a frame always fits inside a single noise message.
+const MAX_FRAME_LEN: usize = 16 * 1024;
+
+/// Inbound streams that have not been accepted yet, any further stream the remote peer opens is reset.
+pub const MAX_PENDING_STREAMS: usize = 256;
+
+/// Unread bytes a single stream buffers, a stream the remote peer sends more to is reset.
+pub const MAX_BUFFERED_BYTES: usize = MAX_MESSAGE_LEN;
+
+#[derive(Error, Debug)]
+pub enum MplexError {
+    #[error("mplex flag {0} is not defined")]
+    InvalidFlag(u64),
+    #[error("mplex message of {0} bytes exceeds the 1MiB limit")]
+    MessageTooLarge(usize),
+    #[error("stream {0} is closed")]
+    StreamClosed(u64),
+    #[error("stream {0} was reset")]
+    StreamReset(u64),
+}
+
+/// The flag carried in the low 3 bits of every mplex header. `Receiver` flags are sent by the
+/// peer that accepted the stream and `Initiator` flags by the peer that opened it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Flag {
+    NewStream = 0,
+    MessageReceiver = 1,
+    MessageInitiator = 2,
+    CloseReceiver = 3,
+    CloseInitiator = 4,
+    ResetReceiver = 5,
+    ResetInitiator = 6,
+}
+
+impl TryFrom<u64> for Flag {
+    type Error = MplexError;
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Flag::NewStream),
+            1 => Ok(Flag::MessageReceiver),
+            2 => Ok(Flag::MessageInitiator),
+            3 => Ok(Flag::CloseReceiver),
+            4 => Ok(Flag::CloseInitiator),
+            5 => Ok(Flag::ResetReceiver),
+            6 => Ok(Flag::ResetInitiator),
+            _ => Err(MplexError::InvalidFlag(value)),
+        }
+    }
+}
+
+/// Both peers number the streams they open from 0, so a stream is identified by its number
+/// together with whether the local peer opened it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct StreamId {
+    id: u64,
+    local: bool,
+}
+
+impl StreamId {
+    /// Picks the `Initiator` or `Receiver` variant of a flag depending on who opened the stream.
+    fn flag(&self, initiator: Flag, receiver: Flag) -> Flag {
+        if self.local {
+            initiator
+        } else {
+            receiver
+        }
+    }
+}
+
+/// A frame decoded from the session buffer, `len` counts the header and length varints as well as the data.
+struct Frame {
+    header: u64,
+    data: Vec<u8>,
+    len: usize,
+}
+
+#[derive(Default)]
+struct StreamState {
+    messages: VecDeque<Vec<u8>>,
+    /// The number of bytes in `messages`
+    buffered: usize,
+    local_closed: bool,
+    remote_closed: bool,
+    reset: bool,
+}
+
+/// The state shared by an [Mplex] session and all of its [MplexStream]s.
+struct Session<'a> {
+    channel: Box<dyn SecureChannel + 'a>,
+    buffer: Vec<u8>,
+    streams: HashMap<StreamId, StreamState>,
+    inbound: VecDeque<StreamId>,
+    next_id: u64,
+}
+
+impl<'a> Session<'a> {
+    /// Writes a frame made of the varint `id << 3 | flag` header, a varint length and the data.
+    fn write_frame(&mut self, id: u64, flag: Flag, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        let (mut header, mut length) = (encode::u64_buffer(), encode::usize_buffer());
+        let header = encode::u64(id << 3 | flag as u64, &mut header);
+        let length = encode::usize(data.len(), &mut length);
+        self.channel.write(&[header, length, data].concat())
+    }
+
+    /// Reads a single frame, channel messages need not line up with frames.
+    fn read_frame(&mut self) -> Result<(u64, Flag, Vec<u8>), Box<dyn Error>> {
+        loop {
+            match Self::decode_frame(&self.buffer)? {
+                Some(frame) => {
+                    self.buffer.drain(..frame.len);
+                    return Ok((frame.header >> 3, Flag::try_from(frame.header & 0b111)?, frame.data));
+                }
+                None => {
+                    let mut data = self.channel.read()?;
+                    self.buffer.append(&mut data);
+                }
+            }
+        }
+    }
+
+    /// Decodes the frame at the start of `buffer`, or returns [None] if it has not fully arrived.
+    fn decode_frame(buffer: &[u8]) -> Result<Option<Frame>, Box<dyn Error>> {
+        let (header, rest) = match decode::u64(buffer) {
+            Ok(decoded) => decoded,
+            Err(decode::Error::Insufficient) => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        let (length, rest) = match decode::usize(rest) {
+            Ok(decoded) => decoded,
+            Err(decode::Error::Insufficient) => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        if length > MAX_MESSAGE_LEN {
+            return Err(MplexError::MessageTooLarge(length).into());
+        }
+        if rest.len() < length {
+            return Ok(None);
+        }
+        Ok(Some(Frame {
+            header,
+            data: rest[..length].to_vec(),
+            len: buffer.len() - rest.len() + length,
+        }))
+    }
+
+    /// Reads a single frame from the remote peer and applies it to its stream.
+    fn process_frame(&mut self) -> Result<(), Box<dyn Error>> {
+        let (id, flag, data) = self.read_frame()?;
+        // Messages flagged `Receiver` were sent on a stream the local peer opened
+        let stream_id = match flag {
+            Flag::MessageReceiver | Flag::CloseReceiver | Flag::ResetReceiver => StreamId { id, local: true },
+            _ => StreamId { id, local: false },
+        };
+        if flag == Flag::NewStream {
+            // Opening a stream that is still open is a protocol error, the stream is reset rather than replaced
+            if let Some(stream) = self.streams.get_mut(&stream_id) {
+                stream.reset = true;
+                return self.write_frame(id, Flag::ResetReceiver, &[]);
+            }
+            if self.inbound.len() >= MAX_PENDING_STREAMS {
+                return self.write_frame(id, Flag::ResetReceiver, &[]);
+            }
+            self.streams.insert(stream_id, StreamState::default());
+            self.inbound.push_back(stream_id);
+            return Ok(());
+        }
+        // Frames for streams that have already been released are dropped
+        let Some(stream) = self.streams.get_mut(&stream_id) else {
+            return Ok(());
+        };
+        match flag {
+            Flag::MessageReceiver | Flag::MessageInitiator if stream.reset => {}
+            Flag::MessageReceiver | Flag::MessageInitiator if stream.buffered + data.len() > MAX_BUFFERED_BYTES => {
+                stream.reset = true;
+                stream.messages.clear();
+                stream.buffered = 0;
+                return self.write_frame(id, stream_id.flag(Flag::ResetInitiator, Flag::ResetReceiver), &[]);
+            }
+            Flag::MessageReceiver | Flag::MessageInitiator => {
+                stream.buffered += data.len();
+                stream.messages.push_back(data);
+            }
+            Flag::CloseReceiver | Flag::CloseInitiator => stream.remote_closed = true,
+            Flag::ResetReceiver | Flag::ResetInitiator => stream.reset = true,
+            Flag::NewStream => unreachable!("new streams are handled above"),
+        }
+        Ok(())
+    }
+
+    /// Forgets a stream once neither side can use it any more.
+    fn release(&mut self, stream_id: StreamId) {
+        let finished = self.streams.get(&stream_id).map(|stream| {
+            stream.reset || (stream.local_closed && stream.remote_closed && stream.messages.is_empty())
+        });
+        if finished == Some(true) {
+            self.streams.remove(&stream_id);
+        }
+    }
+}
+
+/// An mplex session multiplexing independent [MplexStream]s over a [SecureChannel].
+///
+/// Like [super::yamux::Yamux] there is no background task, frames are read from the channel
+/// whenever a stream or the session is waiting on the remote peer. Mplex has no flow control, so at most
+/// [MAX_PENDING_STREAMS] inbound streams wait for [Mplex::accept_stream] and a stream buffers at most
+/// [MAX_BUFFERED_BYTES] unread bytes, the remote peer's streams beyond either limit are reset.
+///
+/// See the mplex spec [here](https://github.com/libp2p/specs/tree/master/mplex).
+pub struct Mplex<'a> {
+    session: Rc<RefCell<Session<'a>>>,
+}
+
+impl<'a> Mplex<'a> {
+    /// Starts a session on a channel after `/mplex/6.7.0` has been negotiated.
+    pub fn new(channel: Box<dyn SecureChannel + 'a>) -> Self {
+        Mplex {
+            session: Rc::new(RefCell::new(Session {
+                channel,
+                buffer: Vec::new(),
+                streams: HashMap::new(),
+                inbound: VecDeque::new(),
+                next_id: 0,
+            })),
+        }
+    }
+
+    /// Seeds the session with bytes that were read from the channel during negotiation.
+    pub(crate) fn with_buffer(self, buffer: Vec<u8>) -> Self {
+        self.session.borrow_mut().buffer = buffer;
+        self
+    }
+
+    fn stream(&self, stream_id: StreamId) -> MplexStream<'a> {
+        MplexStream { stream_id, session: self.session.clone() }
+    }
+
+    /// Opens a new outbound stream by sending a NewStream frame, its name is the stream number.
+    pub fn open_stream(&self) -> Result<MplexStream<'a>, Box<dyn Error>> {
+        let mut session = self.session.borrow_mut();
+        let stream_id = StreamId { id: session.next_id, local: true };
+        session.next_id += 1;
+        session.streams.insert(stream_id, StreamState::default());
+        session.write_frame(stream_id.id, Flag::NewStream, stream_id.id.to_string().as_bytes())?;
+        Ok(self.stream(stream_id))
+    }
+
+    /// Blocks until the remote peer opens a stream.
+    pub fn accept_stream(&self) -> Result<MplexStream<'a>, Box<dyn Error>> {
+        let mut session = self.session.borrow_mut();
+        loop {
+            if let Some(stream_id) = session.inbound.pop_front() {
+                return Ok(self.stream(stream_id));
+            }
+            session.process_frame()?;
+        }
+    }
+}
+
+/// A single substream of an [Mplex] session, reading and writing only touch this stream's data.
+pub struct MplexStream<'a> {
+    stream_id: StreamId,
+    session: Rc<RefCell<Session<'a>>>,
+}
+
+impl<'a> MplexStream<'a> {
+    pub fn id(&self) -> u64 {
+        self.stream_id.id
+    }
+
+    /// Half-closes the stream, the remote peer may continue writing.
+    pub fn close(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut session = self.session.borrow_mut();
+        let stream = session
+            .streams
+            .get_mut(&self.stream_id)
+            .ok_or(MplexError::StreamClosed(self.stream_id.id))?;
+        stream.local_closed = true;
+        let flag = self.stream_id.flag(Flag::CloseInitiator, Flag::CloseReceiver);
+        session.write_frame(self.stream_id.id, flag, &[])?;
+        session.release(self.stream_id);
+        Ok(())
+    }
+
+    /// Abruptly closes both directions of the stream.
+    pub fn reset(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut session = self.session.borrow_mut();
+        session.streams.remove(&self.stream_id);
+        let flag = self.stream_id.flag(Flag::ResetInitiator, Flag::ResetReceiver);
+        session.write_frame(self.stream_id.id, flag, &[])
+    }
+}
+
+impl<'a> SecureChannel for MplexStream<'a> {
+    /// Writes `data` as one or more message frames.
+    fn write(&mut self, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mut session = self.session.borrow_mut();
+        let stream = session
+            .streams
+            .get(&self.stream_id)
+            .ok_or(MplexError::StreamClosed(self.stream_id.id))?;
+        if stream.reset {
+            return Err(MplexError::StreamReset(self.stream_id.id).into());
+        }
+        if stream.local_closed {
+            return Err(MplexError::StreamClosed(self.stream_id.id).into());
+        }
+        let flag = self.stream_id.flag(Flag::MessageInitiator, Flag::MessageReceiver);
+        for chunk in data.chunks(MAX_FRAME_LEN) {
+            session.write_frame(self.stream_id.id, flag, chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the next message received on this stream, or blocks until one arrives.
+    /// An empty result means the remote peer has closed the stream.
+    fn read(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut session = self.session.borrow_mut();
+        loop {
+            let stream = session
+                .streams
+                .get_mut(&self.stream_id)
+                .ok_or(MplexError::StreamClosed(self.stream_id.id))?;
+            if stream.reset {
+                session.streams.remove(&self.stream_id);
+                return Err(MplexError::StreamReset(self.stream_id.id).into());
+            }
+            if let Some(message) = stream.messages.pop_front() {
+                stream.buffered -= message.len();
+                return Ok(message);
+            }
+            if stream.remote_closed {
+                session.release(self.stream_id);
+                return Ok(Vec::new());
+            }
+            session.process_frame()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use unsigned_varint::encode;
+
+    use super::{Flag, Mplex, MplexError, MAX_BUFFERED_BYTES, MAX_FRAME_LEN, MAX_PENDING_STREAMS};
+    use crate::{auth::SecureChannel, muxer::memory};
+
+    fn frame(id: u64, flag: Flag, data: &[u8]) -> Vec<u8> {
+        let (mut header, mut length) = (encode::u64_buffer(), encode::usize_buffer());
+        [encode::u64(id << 3 | flag as u64, &mut header), encode::usize(data.len(), &mut length), data].concat()
+    }
+
+    #[test]
+    fn test_streams() {
+        let (local, remote) = memory::pair();
+
+        let responder = thread::spawn(move || {
+            let mplex = Mplex::new(Box::new(remote));
+            let mut inbound = mplex.accept_stream().unwrap();
+            assert_eq!(inbound.read().unwrap(), b"ping");
+            inbound.write(b"pong").unwrap();
+            assert!(inbound.read().unwrap().is_empty());
+            inbound.close().unwrap();
+
+            // The responder numbers its own streams from 0 as well
+            let mut outbound = mplex.open_stream().unwrap();
+            assert_eq!(outbound.id(), 0);
+            outbound.write(b"from responder").unwrap();
+            assert!(outbound.read().is_err());
+        });
+
+        let mplex = Mplex::new(Box::new(local));
+        let mut outbound = mplex.open_stream().unwrap();
+        outbound.write(b"ping").unwrap();
+        assert_eq!(outbound.read().unwrap(), b"pong");
+        outbound.close().unwrap();
+        assert!(outbound.read().unwrap().is_empty());
+
+        let mut inbound = mplex.accept_stream().unwrap();
+        assert_eq!(inbound.id(), 0);
+        assert_eq!(inbound.read().unwrap(), b"from responder");
+        inbound.reset().unwrap();
+        responder.join().unwrap();
+    }
+
+    #[test]
+    fn test_duplicate_new_stream() {
+        let (local, mut remote) = memory::pair();
+        // Stream 0 is opened twice, each frame is the header `0 << 3 | NewStream`, a length and the name
+        remote.write(&[0, 1, b'0']).unwrap();
+        remote.write(&[0, 1, b'0']).unwrap();
+
+        let mplex = Mplex::new(Box::new(local));
+        let mut inbound = mplex.accept_stream().unwrap();
+        let error = inbound.read().unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(MplexError::StreamReset(0))));
+        // The header of the reset is `0 << 3 | ResetReceiver`
+        assert_eq!(remote.read().unwrap(), [5, 0]);
+    }
+
+    #[test]
+    fn test_pending_stream_limit() {
+        let (local, mut remote) = memory::pair();
+        let last = MAX_PENDING_STREAMS as u64;
+        for id in 0..=last {
+            remote.write(&frame(id, Flag::NewStream, id.to_string().as_bytes())).unwrap();
+        }
+        // Reading a stream of our own processes every frame the remote peer sent before it
+        remote.write(&frame(0, Flag::MessageReceiver, b"done")).unwrap();
+
+        let mplex = Mplex::new(Box::new(local));
+        let mut outbound = mplex.open_stream().unwrap();
+        assert_eq!(outbound.read().unwrap(), b"done");
+        assert_eq!(remote.read().unwrap(), frame(0, Flag::NewStream, b"0"));
+        // Only the stream beyond the limit is reset
+        assert_eq!(remote.read().unwrap(), frame(last, Flag::ResetReceiver, &[]));
+        for id in 0..last {
+            assert_eq!(mplex.accept_stream().unwrap().id(), id);
+        }
+    }
+
+    #[test]
+    fn test_buffered_bytes_limit() {
+        let (local, mut remote) = memory::pair();
+        remote.write(&frame(0, Flag::NewStream, b"0")).unwrap();
+        for _ in 0..=MAX_BUFFERED_BYTES / MAX_FRAME_LEN {
+            remote.write(&frame(0, Flag::MessageInitiator, &[0; MAX_FRAME_LEN])).unwrap();
+        }
+        remote.write(&frame(0, Flag::MessageReceiver, b"done")).unwrap();
+
+        let mplex = Mplex::new(Box::new(local));
+        let mut inbound = mplex.accept_stream().unwrap();
+        let mut outbound = mplex.open_stream().unwrap();
+        assert_eq!(outbound.read().unwrap(), b"done");
+        assert_eq!(remote.read().unwrap(), frame(0, Flag::NewStream, b"0"));
+        assert_eq!(remote.read().unwrap(), frame(0, Flag::ResetReceiver, &[]));
+        let error = inbound.read().unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(MplexError::StreamReset(0))));
+    }
+}
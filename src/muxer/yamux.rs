@@ -0,0 +1,569 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    error::Error,
+    rc::Rc,
+};
+
+use thiserror::Error;
+
+use crate::auth::SecureChannel;
+
+const VERSION: u8 = 0;
+const HEADER_LEN: usize = 12;
+
+/// Every stream starts with a 256KiB window in each direction.
+pub const INITIAL_WINDOW: u32 = 256 * 1024;
+
+/// Data frames are split so that a frame always fits inside a single noise message.
+const MAX_FRAME_LEN: usize = 16 * 1024;
+
+/// Inbound streams that have not been accepted yet, any further stream the remote peer opens is reset.
+pub const MAX_PENDING_STREAMS: usize = 256;
+
+const SYN: u16 = 0x1;
+const ACK: u16 = 0x2;
+const FIN: u16 = 0x4;
+const RST: u16 = 0x8;
+
+#[derive(Error, Debug)]
+pub enum YamuxError {
+    #[error("yamux version {0} is not supported")]
+    InvalidVersion(u8),
+    #[error("yamux frame type {0} is not defined")]
+    InvalidFrameType(u8),
+    #[error("stream {0} uses an id reserved for the local peer or the session")]
+    InvalidStreamId(u32),
+    #[error("stream {0} received more data than its receive window allows")]
+    WindowExceeded(u32),
+    #[error("stream {0} is closed")]
+    StreamClosed(u32),
+    #[error("stream {0} was reset")]
+    StreamReset(u32),
+    #[error("the remote peer is going away with code {0}")]
+    GoAway(u32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FrameType {
+    Data = 0,
+    WindowUpdate = 1,
+    Ping = 2,
+    GoAway = 3,
+}
+
+impl TryFrom<u8> for FrameType {
+    type Error = YamuxError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(FrameType::Data),
+            1 => Ok(FrameType::WindowUpdate),
+            2 => Ok(FrameType::Ping),
+            3 => Ok(FrameType::GoAway),
+            _ => Err(YamuxError::InvalidFrameType(value)),
+        }
+    }
+}
+
+/// The reason sent with a GoAway frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GoAwayCode {
+    Normal = 0,
+    ProtocolError = 1,
+    InternalError = 2,
+}
+
+/// The 12 byte header that starts every yamux frame, all fields are big-endian.
+///
+/// `length` is the size of the body for data frames, the window delta for window updates,
+/// the opaque value of a ping and the error code of a go away.
+#[derive(Debug, PartialEq, Eq)]
+struct Header {
+    frame_type: FrameType,
+    flags: u16,
+    stream_id: u32,
+    length: u32,
+}
+
+impl Header {
+    fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0u8; HEADER_LEN];
+        bytes[0] = VERSION;
+        bytes[1] = self.frame_type as u8;
+        bytes[2..4].copy_from_slice(&self.flags.to_be_bytes());
+        bytes[4..8].copy_from_slice(&self.stream_id.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.length.to_be_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8; HEADER_LEN]) -> Result<Self, YamuxError> {
+        if bytes[0] != VERSION {
+            return Err(YamuxError::InvalidVersion(bytes[0]));
+        }
+        Ok(Header {
+            frame_type: FrameType::try_from(bytes[1])?,
+            flags: u16::from_be_bytes([bytes[2], bytes[3]]),
+            stream_id: u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+            length: u32::from_be_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+        })
+    }
+}
+
+struct StreamState {
+    buffer: Vec<u8>,
+    recv_window: u32,
+    send_window: u32,
+    local_closed: bool,
+    remote_closed: bool,
+    reset: bool,
+}
+
+impl StreamState {
+    fn new() -> Self {
+        StreamState {
+            buffer: Vec::new(),
+            recv_window: INITIAL_WINDOW,
+            send_window: INITIAL_WINDOW,
+            local_closed: false,
+            remote_closed: false,
+            reset: false,
+        }
+    }
+}
+
+/// The state shared by a [Yamux] session and all of its [YamuxStream]s.
+struct Session<'a> {
+    channel: Box<dyn SecureChannel + 'a>,
+    buffer: Vec<u8>,
+    streams: HashMap<u32, StreamState>,
+    inbound: VecDeque<u32>,
+    next_id: u32,
+    next_ping: u32,
+    pongs: Vec<u32>,
+    go_away: Option<u32>,
+}
+
+impl<'a> Session<'a> {
+    fn write_frame(&mut self, header: Header, body: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.channel.write(&[&header.encode()[..], body].concat())
+    }
+
+    /// Reads from the channel until `len` bytes are buffered, channel messages need not line up with frames.
+    fn fill(&mut self, len: usize) -> Result<(), Box<dyn Error>> {
+        while self.buffer.len() < len {
+            let mut data = self.channel.read()?;
+            self.buffer.append(&mut data);
+        }
+        Ok(())
+    }
+
+    fn read_header(&mut self) -> Result<Header, Box<dyn Error>> {
+        self.fill(HEADER_LEN)?;
+        let header = Header::decode(self.buffer[..HEADER_LEN].try_into()?)?;
+        self.buffer.drain(..HEADER_LEN);
+        Ok(header)
+    }
+
+    fn read_body(&mut self, len: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.fill(len)?;
+        Ok(self.buffer.drain(..len).collect())
+    }
+
+    /// Reads a single frame from the remote peer and applies it to the session.
+    fn process_frame(&mut self) -> Result<(), Box<dyn Error>> {
+        let header = self.read_header()?;
+        match header.frame_type {
+            FrameType::Data | FrameType::WindowUpdate => self.process_stream_frame(header),
+            FrameType::Ping => {
+                if header.flags & SYN != 0 {
+                    let pong = Header { frame_type: FrameType::Ping, flags: ACK, stream_id: 0, length: header.length };
+                    self.write_frame(pong, &[])?;
+                } else if header.flags & ACK != 0 {
+                    self.pongs.push(header.length);
+                }
+                Ok(())
+            }
+            FrameType::GoAway => {
+                self.go_away = Some(header.length);
+                Ok(())
+            }
+        }
+    }
+
+    fn process_stream_frame(&mut self, header: Header) -> Result<(), Box<dyn Error>> {
+        let id = header.stream_id;
+        // Stream id 0 is reserved for the session itself
+        if id == 0 {
+            self.go_away(GoAwayCode::ProtocolError)?;
+            return Err(YamuxError::InvalidStreamId(id).into());
+        }
+        let mut refused = false;
+        if header.flags & SYN != 0 && !self.streams.contains_key(&id) {
+            // Streams opened by the remote peer use the opposite parity to our own
+            if id % 2 == self.next_id % 2 {
+                self.go_away(GoAwayCode::ProtocolError)?;
+                return Err(YamuxError::InvalidStreamId(id).into());
+            }
+            if self.inbound.len() < MAX_PENDING_STREAMS {
+                self.streams.insert(id, StreamState::new());
+                self.inbound.push_back(id);
+            } else {
+                refused = true;
+            }
+        }
+        let body = if header.frame_type == FrameType::Data {
+            // The window is checked before the body is buffered, frames for unknown streams get a fresh window
+            let recv_window = self.streams.get(&id).map_or(INITIAL_WINDOW, |stream| stream.recv_window);
+            if header.length > recv_window {
+                self.go_away(GoAwayCode::ProtocolError)?;
+                return Err(YamuxError::WindowExceeded(id).into());
+            }
+            self.read_body(header.length as usize)?
+        } else {
+            Vec::new()
+        };
+        if refused {
+            let reset = Header { frame_type: FrameType::WindowUpdate, flags: RST, stream_id: id, length: 0 };
+            return self.write_frame(reset, &[]);
+        }
+        // Frames for streams that have already been released are dropped
+        let Some(stream) = self.streams.get_mut(&id) else {
+            return Ok(());
+        };
+        if header.frame_type == FrameType::Data {
+            stream.recv_window -= header.length;
+            stream.buffer.extend(body);
+        } else {
+            stream.send_window = stream.send_window.saturating_add(header.length);
+        }
+        if header.flags & FIN != 0 {
+            stream.remote_closed = true;
+        }
+        if header.flags & RST != 0 {
+            stream.reset = true;
+        }
+        Ok(())
+    }
+
+    /// Restores the receive window of a stream once the reader has consumed at least half of it.
+    fn update_window(&mut self, id: u32) -> Result<(), Box<dyn Error>> {
+        let Some(stream) = self.streams.get_mut(&id) else {
+            return Ok(());
+        };
+        if stream.remote_closed || stream.recv_window >= INITIAL_WINDOW / 2 {
+            return Ok(());
+        }
+        let delta = INITIAL_WINDOW - stream.recv_window;
+        stream.recv_window = INITIAL_WINDOW;
+        let update = Header { frame_type: FrameType::WindowUpdate, flags: 0, stream_id: id, length: delta };
+        self.write_frame(update, &[])
+    }
+
+    /// Forgets a stream once neither side can use it any more.
+    fn release(&mut self, id: u32) {
+        let finished = self
+            .streams
+            .get(&id)
+            .map(|stream| stream.reset || (stream.local_closed && stream.remote_closed && stream.buffer.is_empty()));
+        if finished == Some(true) {
+            self.streams.remove(&id);
+        }
+    }
+
+    fn go_away(&mut self, code: GoAwayCode) -> Result<(), Box<dyn Error>> {
+        let go_away = Header { frame_type: FrameType::GoAway, flags: 0, stream_id: 0, length: code as u32 };
+        self.write_frame(go_away, &[])
+    }
+}
+
+/// A yamux session multiplexing independent [YamuxStream]s over a [SecureChannel].
+///
+/// The session has no background task, frames are read from the channel whenever a stream or the
+/// session itself is waiting on the remote peer, and pings are answered as they are read. At most
+/// [MAX_PENDING_STREAMS] inbound streams wait for [Yamux::accept_stream], later ones are reset.
+///
+/// Streams are independent in that each one has its own data, windows and close state, but they share the
+/// session through an `Rc<RefCell<_>>`. Neither the session nor its streams are `Send`, so they all live on the
+/// thread that created the session. A stream blocked in [SecureChannel::read] buffers the frames of the
+/// other streams until its own data arrives.
+///
+/// See the yamux spec [here](https://github.com/hashicorp/yamux/blob/master/spec.md).
+pub struct Yamux<'a> {
+    session: Rc<RefCell<Session<'a>>>,
+}
+
+impl<'a> Yamux<'a> {
+    /// Starts a session on a channel after `/yamux/1.0.0` has been negotiated,
+    /// the `initiator` of the connection opens odd stream ids and the responder even ones.
+    pub fn new(channel: Box<dyn SecureChannel + 'a>, initiator: bool) -> Self {
+        Yamux {
+            session: Rc::new(RefCell::new(Session {
+                channel,
+                buffer: Vec::new(),
+                streams: HashMap::new(),
+                inbound: VecDeque::new(),
+                next_id: if initiator { 1 } else { 2 },
+                next_ping: 0,
+                pongs: Vec::new(),
+                go_away: None,
+            })),
+        }
+    }
+
+    /// Seeds the session with bytes that were read from the channel during negotiation.
+    pub(crate) fn with_buffer(self, buffer: Vec<u8>) -> Self {
+        self.session.borrow_mut().buffer = buffer;
+        self
+    }
+
+    fn stream(&self, id: u32) -> YamuxStream<'a> {
+        YamuxStream { id, session: self.session.clone() }
+    }
+
+    /// Opens a new outbound stream by sending a window update with the SYN flag.
+    pub fn open_stream(&self) -> Result<YamuxStream<'a>, Box<dyn Error>> {
+        let mut session = self.session.borrow_mut();
+        if let Some(code) = session.go_away {
+            return Err(YamuxError::GoAway(code).into());
+        }
+        let id = session.next_id;
+        session.next_id += 2;
+        session.streams.insert(id, StreamState::new());
+        session.write_frame(Header { frame_type: FrameType::WindowUpdate, flags: SYN, stream_id: id, length: 0 }, &[])?;
+        Ok(self.stream(id))
+    }
+
+    /// Blocks until the remote peer opens a stream and acknowledges it.
+    pub fn accept_stream(&self) -> Result<YamuxStream<'a>, Box<dyn Error>> {
+        let mut session = self.session.borrow_mut();
+        loop {
+            if let Some(id) = session.inbound.pop_front() {
+                let ack = Header { frame_type: FrameType::WindowUpdate, flags: ACK, stream_id: id, length: 0 };
+                session.write_frame(ack, &[])?;
+                return Ok(self.stream(id));
+            }
+            if let Some(code) = session.go_away {
+                return Err(YamuxError::GoAway(code).into());
+            }
+            session.process_frame()?;
+        }
+    }
+
+    /// Sends a ping and blocks until the remote peer answers it.
+    pub fn ping(&self) -> Result<(), Box<dyn Error>> {
+        let mut session = self.session.borrow_mut();
+        let opaque = session.next_ping;
+        session.next_ping = session.next_ping.wrapping_add(1);
+        session.write_frame(Header { frame_type: FrameType::Ping, flags: SYN, stream_id: 0, length: opaque }, &[])?;
+        while !session.pongs.contains(&opaque) {
+            session.process_frame()?;
+        }
+        session.pongs.retain(|pong| *pong != opaque);
+        Ok(())
+    }
+
+    /// Tells the remote peer that no further streams will be accepted.
+    pub fn go_away(&self, code: GoAwayCode) -> Result<(), Box<dyn Error>> {
+        self.session.borrow_mut().go_away(code)
+    }
+}
+
+/// A single substream of a [Yamux] session, reading and writing only touch this stream's data.
+///
+/// The stream borrows the session's channel, it is not `Send` and must be used on the session's thread.
+pub struct YamuxStream<'a> {
+    id: u32,
+    session: Rc<RefCell<Session<'a>>>,
+}
+
+impl<'a> YamuxStream<'a> {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Half-closes the stream by sending FIN, the remote peer may continue writing.
+    pub fn close(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut session = self.session.borrow_mut();
+        let stream = session.streams.get_mut(&self.id).ok_or(YamuxError::StreamClosed(self.id))?;
+        stream.local_closed = true;
+        session.write_frame(Header { frame_type: FrameType::Data, flags: FIN, stream_id: self.id, length: 0 }, &[])?;
+        session.release(self.id);
+        Ok(())
+    }
+
+    /// Abruptly closes both directions of the stream by sending RST.
+    pub fn reset(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut session = self.session.borrow_mut();
+        session.streams.remove(&self.id);
+        session.write_frame(Header { frame_type: FrameType::WindowUpdate, flags: RST, stream_id: self.id, length: 0 }, &[])
+    }
+}
+
+impl<'a> SecureChannel for YamuxStream<'a> {
+    /// Writes `data` as one or more data frames, blocking while the remote receive window is exhausted.
+    fn write(&mut self, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mut session = self.session.borrow_mut();
+        let mut remaining = data;
+        while !remaining.is_empty() {
+            let stream = session.streams.get_mut(&self.id).ok_or(YamuxError::StreamClosed(self.id))?;
+            if stream.reset {
+                return Err(YamuxError::StreamReset(self.id).into());
+            }
+            if stream.local_closed {
+                return Err(YamuxError::StreamClosed(self.id).into());
+            }
+            if stream.send_window == 0 {
+                session.process_frame()?;
+                continue;
+            }
+            let len = remaining.len().min(stream.send_window as usize).min(MAX_FRAME_LEN);
+            stream.send_window -= len as u32;
+            let header = Header { frame_type: FrameType::Data, flags: 0, stream_id: self.id, length: len as u32 };
+            session.write_frame(header, &remaining[..len])?;
+            remaining = &remaining[len..];
+        }
+        Ok(())
+    }
+
+    /// Returns the data buffered for this stream, or blocks until some arrives.
+    /// An empty result means the remote peer has closed the stream.
+    fn read(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut session = self.session.borrow_mut();
+        loop {
+            let stream = session.streams.get_mut(&self.id).ok_or(YamuxError::StreamClosed(self.id))?;
+            if stream.reset {
+                session.streams.remove(&self.id);
+                return Err(YamuxError::StreamReset(self.id).into());
+            }
+            if !stream.buffer.is_empty() {
+                let data = std::mem::take(&mut stream.buffer);
+                session.update_window(self.id)?;
+                return Ok(data);
+            }
+            if stream.remote_closed {
+                session.release(self.id);
+                return Ok(Vec::new());
+            }
+            session.process_frame()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::{
+        FrameType, GoAwayCode, Header, Yamux, YamuxError, ACK, INITIAL_WINDOW, MAX_PENDING_STREAMS, RST, SYN,
+    };
+    use crate::{auth::SecureChannel, muxer::memory};
+
+    #[test]
+    fn test_header() {
+        let header = Header { frame_type: FrameType::WindowUpdate, flags: SYN, stream_id: 2, length: 0 };
+        let bytes = header.encode();
+        assert_eq!(bytes, [0, 1, 0, 1, 0, 0, 0, 2, 0, 0, 0, 0]);
+        assert_eq!(Header::decode(&bytes).unwrap(), header);
+        assert!(Header::decode(&[1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).is_err());
+        assert!(Header::decode(&[0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_streams() {
+        let (local, remote) = memory::pair();
+        // Large enough to exhaust the initial window several times over
+        let large = vec![7u8; INITIAL_WINDOW as usize * 3];
+        let expected = large.clone();
+
+        let responder = thread::spawn(move || {
+            let yamux = Yamux::new(Box::new(remote), false);
+            let mut first = yamux.accept_stream().unwrap();
+            let mut second = yamux.accept_stream().unwrap();
+            assert_eq!((first.id(), second.id()), (1, 3));
+
+            assert_eq!(second.read().unwrap(), b"second");
+            let mut received = Vec::new();
+            loop {
+                let data = first.read().unwrap();
+                if data.is_empty() {
+                    break;
+                }
+                received.extend(data);
+            }
+            assert_eq!(received, expected);
+            second.write(b"reply").unwrap();
+            yamux.go_away(GoAwayCode::Normal).unwrap();
+        });
+
+        let yamux = Yamux::new(Box::new(local), true);
+        yamux.ping().unwrap();
+        let mut first = yamux.open_stream().unwrap();
+        let mut second = yamux.open_stream().unwrap();
+        second.write(b"second").unwrap();
+        first.write(&large).unwrap();
+        first.close().unwrap();
+        assert!(first.write(b"closed").is_err());
+        assert_eq!(second.read().unwrap(), b"reply");
+        responder.join().unwrap();
+
+        // The go away frame is read by accept_stream, after which no new streams may be opened
+        assert!(yamux.accept_stream().is_err());
+        assert!(yamux.open_stream().is_err());
+    }
+
+    #[test]
+    fn test_window_checked_before_body() {
+        let (local, mut remote) = memory::pair();
+        // No body follows, the frame must be refused from its header alone
+        let oversized = Header { frame_type: FrameType::Data, flags: SYN, stream_id: 2, length: INITIAL_WINDOW + 1 };
+        remote.write(&oversized.encode()).unwrap();
+
+        let yamux = Yamux::new(Box::new(local), true);
+        let error = yamux.accept_stream().err().unwrap();
+        assert!(matches!(error.downcast_ref(), Some(YamuxError::WindowExceeded(2))));
+    }
+
+    #[test]
+    fn test_session_stream_id() {
+        let (local, mut remote) = memory::pair();
+        // The dialer opens odd ids, so id 0 has the listener's parity but is reserved for the session
+        for frame_type in [FrameType::WindowUpdate, FrameType::Data] {
+            remote.write(&Header { frame_type, flags: SYN, stream_id: 0, length: 0 }.encode()).unwrap();
+        }
+
+        let yamux = Yamux::new(Box::new(local), true);
+        for _ in 0..2 {
+            let error = yamux.accept_stream().err().unwrap();
+            assert!(matches!(error.downcast_ref(), Some(YamuxError::InvalidStreamId(0))));
+            let go_away = Header::decode(remote.read().unwrap()[..].try_into().unwrap()).unwrap();
+            assert_eq!((go_away.frame_type, go_away.length), (FrameType::GoAway, GoAwayCode::ProtocolError as u32));
+        }
+    }
+
+    #[test]
+    fn test_pending_stream_limit() {
+        let (local, mut remote) = memory::pair();
+        let last = 2 * (MAX_PENDING_STREAMS as u32 + 1);
+        let frame = |frame_type, flags, stream_id, length| Header { frame_type, flags, stream_id, length }.encode();
+        let peer = thread::spawn(move || {
+            for id in (2..=last).step_by(2) {
+                remote.write(&frame(FrameType::WindowUpdate, SYN, id, 0)).unwrap();
+            }
+            let ping = Header::decode(remote.read().unwrap()[..].try_into().unwrap()).unwrap();
+            assert_eq!((ping.frame_type, ping.flags), (FrameType::Ping, SYN));
+            // Only the stream beyond the limit is reset
+            let reset = Header::decode(remote.read().unwrap()[..].try_into().unwrap()).unwrap();
+            assert_eq!((reset.flags, reset.stream_id), (RST, last));
+            remote.write(&frame(FrameType::Ping, ACK, 0, ping.length)).unwrap();
+            remote
+        });
+
+        let yamux = Yamux::new(Box::new(local), true);
+        yamux.ping().unwrap();
+        let _remote = peer.join().unwrap();
+        for id in (2..last).step_by(2) {
+            assert_eq!(yamux.accept_stream().unwrap().id(), id);
+        }
+    }
+}
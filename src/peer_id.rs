@@ -0,0 +1,184 @@
+//! Peer identities as used by libp2p to address nodes.
+//!
+//! See the peer id spec [here](https://github.com/libp2p/specs/blob/master/peer-ids/peer-ids.md).
+use std::{error::Error, fmt, str::FromStr};
+
+use multibase::Base;
+use prost::Message;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use unsigned_varint::{decode, encode};
+
+use crate::handshake;
+
+/// Multihash code of the identity hash, the digest is the input itself.
+const IDENTITY: u64 = 0x00;
+/// Multihash code of SHA-256.
+const SHA2_256: u64 = 0x12;
+/// Encoded public keys up to this length are inlined in the peer id rather than hashed.
+const MAX_INLINE_KEY_LENGTH: usize = 42;
+/// Multicodec of CIDs that hold a peer id.
+const LIBP2P_KEY: u64 = 0x72;
+const CID_VERSION: u64 = 1;
+
+#[derive(Error, Debug)]
+pub enum PeerIdError {
+    #[error("a peer id must be an identity or sha2-256 multihash")]
+    InvalidMultihash(),
+    #[error("could not decode the peer id from {0}")]
+    InvalidEncoding(String),
+    #[error("a peer id CID must be a version 1 CID with the libp2p-key codec")]
+    InvalidCid(),
+}
+
+/// The identity of a libp2p peer, a multihash of its protobuf encoded [handshake::PublicKey].
+///
+/// A [PeerId] displays as base58btc, e.g. `12D3KooW...` for Ed25519 keys, and parses from both that
+/// form and a CIDv1 with the `libp2p-key` codec as produced by [PeerId::to_cid].
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct PeerId {
+    multihash: Vec<u8>,
+}
+
+impl PeerId {
+    /// Derives the peer id of a public key, keys whose encoding is at most 42 bytes are inlined with the
+    /// identity hash and larger ones, such as RSA keys, are hashed with SHA-256.
+    pub fn from_public_key(key: &handshake::PublicKey) -> Self {
+        let encoded = key.encode_to_vec();
+        let multihash = if encoded.len() <= MAX_INLINE_KEY_LENGTH {
+            encode_multihash(IDENTITY, &encoded)
+        } else {
+            encode_multihash(SHA2_256, &Sha256::digest(&encoded))
+        };
+        PeerId { multihash }
+    }
+
+    /// Parses a peer id from its binary multihash.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let (code, rest) = decode::u64(bytes)?;
+        let (len, digest) = decode::usize(rest)?;
+        let valid = match code {
+            IDENTITY => len <= MAX_INLINE_KEY_LENGTH,
+            SHA2_256 => len == 32,
+            _ => false,
+        };
+        if !valid || digest.len() != len {
+            return Err(PeerIdError::InvalidMultihash().into());
+        }
+        Ok(PeerId { multihash: bytes.to_vec() })
+    }
+
+    /// The binary multihash of the peer id.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.multihash
+    }
+
+    /// The public key inlined in the peer id, [None] when the key was too large and only its hash is known.
+    pub fn public_key(&self) -> Option<handshake::PublicKey> {
+        let (code, rest) = decode::u64(&self.multihash).ok()?;
+        let (_, digest) = decode::usize(rest).ok()?;
+        if code != IDENTITY {
+            return None;
+        }
+        handshake::PublicKey::decode(digest).ok()
+    }
+
+    /// The base58btc encoding of the multihash, the usual text form of a peer id.
+    pub fn to_base58(&self) -> String {
+        bs58::encode(&self.multihash).into_string()
+    }
+
+    /// The peer id as a CIDv1 with the `libp2p-key` codec, multibase encoded as base32.
+    pub fn to_cid(&self) -> String {
+        let mut buf = encode::u64_buffer();
+        let mut cid = encode::u64(CID_VERSION, &mut buf).to_vec();
+        cid.extend_from_slice(encode::u64(LIBP2P_KEY, &mut buf));
+        cid.extend_from_slice(&self.multihash);
+        multibase::encode(Base::Base32Lower, cid)
+    }
+
+    fn from_cid(cid: &str) -> Result<Self, Box<dyn Error>> {
+        let (_, cid) = multibase::decode(cid)?;
+        let (version, rest) = decode::u64(&cid)?;
+        let (codec, multihash) = decode::u64(rest)?;
+        if version != CID_VERSION || codec != LIBP2P_KEY {
+            return Err(PeerIdError::InvalidCid().into());
+        }
+        Self::from_bytes(multihash)
+    }
+}
+
+impl FromStr for PeerId {
+    type Err = Box<dyn Error>;
+
+    /// Parses either a base58btc peer id, which starts with `1` or `Qm`, or a multibase encoded CIDv1.
+    fn from_str(peer_id: &str) -> Result<Self, Self::Err> {
+        let result = if peer_id.starts_with('1') || peer_id.starts_with("Qm") {
+            bs58::decode(peer_id)
+                .into_vec()
+                .map_err(Into::into)
+                .and_then(|bytes| Self::from_bytes(&bytes))
+        } else {
+            Self::from_cid(peer_id)
+        };
+        result.map_err(|_| PeerIdError::InvalidEncoding(peer_id.to_owned()).into())
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_base58())
+    }
+}
+
+impl fmt::Debug for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("PeerId").field(&self.to_base58()).finish()
+    }
+}
+
+fn encode_multihash(code: u64, digest: &[u8]) -> Vec<u8> {
+    let mut code_buf = encode::u64_buffer();
+    let mut len_buf = encode::usize_buffer();
+    [encode::u64(code, &mut code_buf), encode::usize(digest.len(), &mut len_buf), digest].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PeerId;
+    use crate::handshake;
+
+    #[test]
+    fn test_peer_id() {
+        // Ed25519 key from the peer id spec, small enough to be inlined
+        let key = handshake::PublicKey {
+            r#type: handshake::KeyType::Ed25519 as i32,
+            data: (0..32)
+                .map(|index| {
+                    let hex = "1ed1e8fae2c4a144b8be8fd4b47bf3d3b34b871c3cacf6010f0e42d474fce27e";
+                    u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16).unwrap()
+                })
+                .collect(),
+        };
+        let peer_id = PeerId::from_public_key(&key);
+        assert_eq!(peer_id.to_string(), "12D3KooWBtg3aaRMjxwedh83aGiUkwSxDwUZkzuJcfaqUmo7R3pq");
+        assert_eq!(peer_id.to_cid(), "bafzaajaiaejcahwr5d5ofrfbis4l5d6uwr57hu5tjodrypfm6yaq6dsc2r2pzyt6");
+        assert_eq!(peer_id.public_key(), Some(key));
+        assert_eq!("12D3KooWBtg3aaRMjxwedh83aGiUkwSxDwUZkzuJcfaqUmo7R3pq".parse::<PeerId>().unwrap(), peer_id);
+        assert_eq!(peer_id.to_cid().parse::<PeerId>().unwrap(), peer_id);
+
+        // Larger keys are hashed with SHA-256
+        let key = handshake::PublicKey {
+            r#type: handshake::KeyType::Rsa as i32,
+            data: (0..=255).chain([0; 44]).collect(),
+        };
+        let peer_id = PeerId::from_public_key(&key);
+        assert_eq!(peer_id.to_string(), "QmdhYmyeKAxvW8GhTd8MuYfCGwhjzu1V7DCP7HmToSjRRY");
+        assert_eq!(peer_id.to_cid(), "bafzbeihehhfmrmcjswrwxce3fktmqdvjj5toci3remwy7hhbqmalft2bj4");
+        assert_eq!(peer_id.public_key(), None);
+        assert_eq!(peer_id.to_cid().parse::<PeerId>().unwrap(), peer_id);
+
+        assert!("12D3KooWinvalid".parse::<PeerId>().is_err());
+        assert!("bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi".parse::<PeerId>().is_err());
+    }
+}
@@ -2,12 +2,12 @@
 mod noise {
     use std::{env, net::SocketAddr};
 
-    use ed25519_dalek::Keypair;
+    use noise_handshake::identity::Keypair;
     use noise_handshake::{
         auth::{noise::protocol::NoiseProtocol, AuthProtocol},
         connection::{multistream::Multistream, Connection},
+        muxer::{Muxer, MuxerProtocol},
     };
-    use rand::rngs::OsRng;
 
     #[test]
     fn test_handshake() {
@@ -16,7 +16,7 @@ mod noise {
             .parse()
             .unwrap();
 
-        let peer_id: Keypair = Keypair::generate(&mut OsRng);
+        let peer_id = Keypair::generate_ed25519();
         let connection = Multistream::connect(addr, AuthProtocol::Noise);
 
         assert!(connection.is_ok(), "peer is not reachable");
@@ -24,61 +24,21 @@ mod noise {
 
         // Consumes a connection so that you may only communicate securely
         let secure_channel =
-            Multistream::upgrade::<NoiseProtocol>(connection, peer_id);
+            Multistream::upgrade::<NoiseProtocol>(connection, peer_id, None, &[MuxerProtocol::Yamux]);
 
         assert!(secure_channel.is_ok(), "peer does not support the noise transport");
-        let mut secure_channel = secure_channel.unwrap();
+        let secure_channel = secure_channel.unwrap();
 
-        // Negotiate multistream again over a secure connection
-        let response = secure_channel.read().unwrap();
-        let response = Multistream::deserialize(&response).unwrap();
-        assert!(std::str::from_utf8(&response).unwrap() == "/multistream/1.0.0", "peer does not support multistream protocol");
+        // Negotiate multistream again over a secure connection, then the yamux multiplexer
+        let muxer = Muxer::negotiate(secure_channel, &[MuxerProtocol::Yamux], true);
+        assert!(muxer.is_ok(), "peer does not support yamux multiplexing");
+        let muxer = muxer.unwrap();
 
-        secure_channel.write(&Multistream::serialize(b"/multistream/1.0.0\n")).unwrap();
-
-        // Negotiate yamux multiplexer
-        secure_channel.write(&Multistream::serialize(b"/yamux/1.0.0\n")).unwrap();
-        let response = secure_channel.read().unwrap();
-        let response = Multistream::deserialize(&response).unwrap();
-        assert!(std::str::from_utf8(&response).unwrap() == "/yamux/1.0.0", "peer does not support yamux multiplexing");
-
-        // Setup Yamux connection
-        // SYN
-        secure_channel.write(&encapsulate_yamux(b"", true)).unwrap();
-        let response = secure_channel.read().unwrap();
-        assert!(response == vec![0, 2, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0], "yamux stream not synchronised");
-        
-        // ACK
-        secure_channel.write(&encapsulate_yamux(b"", false)).unwrap();
-        let response = secure_channel.read().unwrap();
-        assert!(response == vec![0, 1, 0, 1, 0, 0, 0, 2, 0, 0, 0, 0], "yamux stream not acknowledged");
+        // Pings are answered while waiting for the peer to open its identify stream
+        let mut stream = muxer.accept_stream().unwrap();
 
         // Get allowable protocols
-        secure_channel.write(&encapsulate_yamux(b"", false)).unwrap();
-        let response = secure_channel.read().unwrap();
-        let response = decapsulate_yamux(&response);
-        print!("YAMUX RESP: {:?}\n", std::str::from_utf8(&response).unwrap());
-        
-    }
-
-    fn encapsulate_yamux(data: &[u8], init: bool,) -> Vec<u8> {
-        let yam_ver = 0u8;
-        let yam_type = 0u8;
-        let yam_flag = if init {1u16.to_be_bytes() } else { 2u16.to_be_bytes() };
-        let yam_stream_id = 3u32.to_be_bytes();
-        let yam_length = (data.len() as i32).to_be_bytes();
-        [
-            &[yam_ver][..],
-            &[yam_type][..],
-            &yam_flag[..],
-            &yam_stream_id[..],
-            &yam_length[..],
-            &data[..]
-        ].concat()
-    }
-
-    fn decapsulate_yamux(data: &[u8]) -> Vec<u8> {
-        data[12..].to_vec()
-
+        let response = stream.read().unwrap();
+        println!("YAMUX RESP: {:?}", std::str::from_utf8(&response).unwrap());
     }
 }
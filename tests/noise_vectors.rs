@@ -0,0 +1,190 @@
+#[cfg(test)]
+mod vectors {
+    //! Replays the upstream Noise test vectors through `HandshakeState`, comparing every handshake and
+    //! transport message as well as the final handshake hash when the vector has one.
+    //!
+    //! `tests/vectors/cacophony.txt` and `tests/vectors/snow.txt` are vendored unchanged from the
+    //! [snow](https://github.com/mcginty/snow) repository, the first comes from
+    //! [cacophony](https://github.com/haskell-cryptography/cacophony). Deferred patterns such as `X1K1` are not
+    //! implemented by the crate and are skipped, every other vector must run.
+    use std::fs;
+
+    use serde::{de, Deserialize, Deserializer};
+
+    use noise_handshake::auth::noise::{
+        crypto::{AesGcm, Blake2b, Blake2s, ChaChaPoly, Cipher, Dh, Hash, Sha256, Sha512, X25519, X448},
+        handshake_state::{HandshakeState, StaticKeypair},
+        pattern::HandshakePattern,
+        PSKLEN,
+    };
+
+    /// A hex encoded byte string.
+    #[derive(Debug, Default)]
+    struct Hex(Vec<u8>);
+
+    impl<'de> Deserialize<'de> for Hex {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let text = String::deserialize(deserializer)?;
+            hex::decode(text).map(Hex).map_err(de::Error::custom)
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct Message {
+        payload: Hex,
+        ciphertext: Hex,
+    }
+
+    #[derive(Deserialize)]
+    struct Vector {
+        protocol_name: String,
+        init_prologue: Hex,
+        #[serde(default)]
+        init_psks: Vec<Hex>,
+        init_static: Option<Hex>,
+        init_ephemeral: Option<Hex>,
+        init_remote_static: Option<Hex>,
+        resp_prologue: Hex,
+        #[serde(default)]
+        resp_psks: Vec<Hex>,
+        resp_static: Option<Hex>,
+        resp_ephemeral: Option<Hex>,
+        resp_remote_static: Option<Hex>,
+        handshake_hash: Option<Hex>,
+        messages: Vec<Message>,
+    }
+
+    #[derive(Deserialize)]
+    struct Vectors {
+        vectors: Vec<Vector>,
+    }
+
+    impl Vector {
+        fn handshake_state<D: Dh, C: Cipher, H: Hash>(
+            &self,
+            pattern: &HandshakePattern,
+            initiator: bool,
+        ) -> HandshakeState<D, C, H> {
+            let (prologue, psks) = match initiator {
+                true => (&self.init_prologue, &self.init_psks),
+                false => (&self.resp_prologue, &self.resp_psks),
+            };
+            let (s, e, rs) = match initiator {
+                true => (&self.init_static, &self.init_ephemeral, &self.init_remote_static),
+                false => (&self.resp_static, &self.resp_ephemeral, &self.resp_remote_static),
+            };
+            let s = match s {
+                Some(Hex(private)) => {
+                    let private = D::private_from_bytes(private).unwrap();
+                    StaticKeypair(D::public_key(&private), private)
+                }
+                None => StaticKeypair::generate(),
+            };
+            let rs = rs.as_ref().map(|Hex(public)| D::public_from_bytes(public).unwrap());
+            let psks = psks
+                .iter()
+                .map(|Hex(psk)| <[u8; PSKLEN]>::try_from(psk.as_slice()).expect("pre-shared keys are 32 bytes"))
+                .collect();
+            let hss = HandshakeState::new(pattern.clone(), initiator, &prologue.0, s, None, rs, None).unwrap();
+            let hss = hss.with_psks(psks);
+            match e {
+                Some(Hex(private)) => hss.with_ephemeral(D::private_from_bytes(private).unwrap()),
+                None => hss,
+            }
+        }
+    }
+
+    fn run<D: Dh, C: Cipher, H: Hash>(vector: &Vector, pattern: HandshakePattern) {
+        let name = &vector.protocol_name;
+        let mut initiator = vector.handshake_state::<D, C, H>(&pattern, true);
+        let mut responder = vector.handshake_state::<D, C, H>(&pattern, false);
+        let handshake_len = pattern.messages().len();
+
+        for (index, message) in vector.messages.iter().take(handshake_len).enumerate() {
+            let (sender, receiver) = if index % 2 == 0 {
+                (&mut initiator, &mut responder)
+            } else {
+                (&mut responder, &mut initiator)
+            };
+            let ciphertext = sender.write_message(&message.payload.0).unwrap();
+            assert_eq!(ciphertext, message.ciphertext.0, "{name}: handshake message {index}");
+            let plaintext = receiver.read_message(&ciphertext).unwrap();
+            assert_eq!(plaintext, message.payload.0, "{name}: handshake message {index}");
+        }
+        if let Some(Hex(handshake_hash)) = &vector.handshake_hash {
+            assert_eq!(&initiator.handshake_hash(), handshake_hash, "{name}: handshake hash");
+        }
+        assert_eq!(initiator.handshake_hash(), responder.handshake_hash());
+
+        // Transport messages alternate from where the handshake left off, one-way patterns only ever send
+        let (mut initiator_send, mut initiator_receive) = initiator.finalize().unwrap();
+        let (mut responder_receive, mut responder_send) = responder.finalize().unwrap();
+        for (index, message) in vector.messages.iter().enumerate().skip(handshake_len) {
+            let (sender, receiver) = if handshake_len == 1 || index % 2 == 0 {
+                (&mut initiator_send, &mut responder_receive)
+            } else {
+                (&mut responder_send, &mut initiator_receive)
+            };
+            let ciphertext = sender.encrypt_with_ad(&[], &message.payload.0).unwrap();
+            assert_eq!(ciphertext, message.ciphertext.0, "{name}: transport message {index}");
+            let plaintext = receiver.decrypt_with_ad(&[], &ciphertext).unwrap();
+            assert_eq!(plaintext, message.payload.0, "{name}: transport message {index}");
+        }
+    }
+
+    fn run_with_hash<D: Dh, C: Cipher>(vector: &Vector, pattern: HandshakePattern, hash: &str) {
+        match hash {
+            "SHA256" => run::<D, C, Sha256>(vector, pattern),
+            "SHA512" => run::<D, C, Sha512>(vector, pattern),
+            "BLAKE2s" => run::<D, C, Blake2s>(vector, pattern),
+            "BLAKE2b" => run::<D, C, Blake2b>(vector, pattern),
+            _ => panic!("{}: unknown hash function {hash}", vector.protocol_name),
+        }
+    }
+
+    fn run_with_cipher<D: Dh>(vector: &Vector, pattern: HandshakePattern, cipher: &str, hash: &str) {
+        match cipher {
+            "ChaChaPoly" => run_with_hash::<D, ChaChaPoly>(vector, pattern, hash),
+            "AESGCM" => run_with_hash::<D, AesGcm>(vector, pattern, hash),
+            _ => panic!("{}: unknown cipher function {cipher}", vector.protocol_name),
+        }
+    }
+
+    /// Runs a vector unless its pattern is a deferred one, returns whether it was run.
+    fn run_vector(vector: &Vector) -> bool {
+        let name = &vector.protocol_name;
+        let parts: Vec<&str> = name.split('_').collect();
+        let [_, pattern, dh, cipher, hash] = parts[..] else {
+            panic!("{name}: not a Noise protocol name");
+        };
+        // Deferred patterns mark the deferred party with a 1, such as `X1K1`, the crate only has the fundamental ones
+        if pattern.split("psk").next().is_some_and(|base| base.contains('1')) {
+            return false;
+        }
+        let pattern = HandshakePattern::new(pattern).unwrap_or_else(|error| panic!("{name}: {error}"));
+        match dh {
+            "25519" => run_with_cipher::<X25519>(vector, pattern, cipher, hash),
+            "448" => run_with_cipher::<X448>(vector, pattern, cipher, hash),
+            _ => panic!("{name}: unknown DH function {dh}"),
+        }
+        true
+    }
+
+    /// Runs every vector in `file`, checking how many were skipped for using deferred patterns.
+    fn run_file(file: &str, expected_skipped: usize) {
+        let path = format!("{}/tests/vectors/{file}", env!("CARGO_MANIFEST_DIR"));
+        let vectors: Vectors = serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        let run = vectors.vectors.iter().filter(|vector| run_vector(vector)).count();
+        assert_eq!(vectors.vectors.len() - run, expected_skipped, "{file}: skipped vectors");
+    }
+
+    #[test]
+    fn test_cacophony_vectors() {
+        run_file("cacophony.txt", 368);
+    }
+
+    #[test]
+    fn test_snow_vectors() {
+        run_file("snow.txt", 184);
+    }
+}